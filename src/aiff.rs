@@ -0,0 +1,196 @@
+use crate::wav::Audio;
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// AIFF store the sample rate as a 80 bits extended float
+fn read_extended(bytes: &[u8], at: usize) -> f64 {
+    let exponent = (read_u16(bytes, at) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes(bytes[at + 2 .. at + 10].try_into().unwrap());
+
+    if exponent == 0 && mantissa == 0 {
+        0.0
+    } else {
+        mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+    }
+}
+
+/// Is the file content looking like an AIFF or AIFF-C file
+pub fn is_aiff(bytes: &[u8]) -> bool {
+    bytes.len() >= 12
+        && &bytes[0..4] == b"FORM"
+        && (&bytes[8..12] == b"AIFF" || &bytes[8..12] == b"AIFC")
+}
+
+/// Find the common and sound chunks of the file
+fn read_chunks(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if !is_aiff(bytes) {
+        return Err(String::from("not an AIFF file"))
+    }
+
+    let mut comm = None;
+    let mut sound = None;
+    let mut cursor = 12;
+
+    while cursor + 8 <= bytes.len() {
+        let chunk_id = &bytes[cursor .. cursor + 4];
+        let chunk_size = read_u32(bytes, cursor + 4) as usize;
+        let chunk_start = cursor + 8;
+        let chunk = &bytes[chunk_start .. (chunk_start + chunk_size).min(bytes.len())];
+
+        match chunk_id {
+            b"COMM" if chunk.len() >= 18 => comm = Some(chunk),
+            b"SSND" if chunk.len() >= 8 => {
+                let offset = read_u32(chunk, 0) as usize;
+                sound = Some(&chunk[(8 + offset).min(chunk.len()) ..]);
+            }
+            _ => {}
+        }
+
+        cursor = chunk_start + chunk_size + (chunk_size & 1);
+    }
+
+    let comm = comm.ok_or(String::from("missing COMM chunk"))?;
+    let sound = sound.ok_or(String::from("missing SSND chunk"))?;
+    Ok((comm, sound))
+}
+
+/// Bit depth of the samples of the file
+pub fn bits_per_sample(bytes: &[u8]) -> Option<u16> {
+    read_chunks(bytes).ok().map(|(comm, _)| read_u16(comm, 6))
+}
+
+/// Decode an uncompressed AIFF or AIFF-C file
+pub fn decode(bytes: &[u8]) -> Result<Audio, String> {
+    let (comm, sound) = read_chunks(bytes)?;
+    let is_aifc = &bytes[8..12] == b"AIFC";
+
+    let channels = read_u16(comm, 0);
+    let frame_count = read_u32(comm, 2) as usize;
+    let bits = read_u16(comm, 6);
+    let sample_rate = read_extended(comm, 8).round() as u32;
+    let compression : &[u8] =
+        if is_aifc && comm.len() >= 22 { &comm[18..22] } else { b"NONE" };
+
+    let bytes_per_sample = (bits as usize).div_ceil(8);
+    let decode_one : fn(&[u8]) -> f32 = match (compression, bytes_per_sample) {
+        (b"NONE" | b"twos", 1) => |b| b[0] as i8 as f32 / 128.0,
+        (b"NONE" | b"twos", 2) => |b| i16::from_be_bytes([b[0], b[1]]) as f32 / 32768.0,
+        (b"NONE", 3) => |b| (i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8) as f32 / 8388608.0,
+        (b"NONE", 4) => |b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+        (b"sowt", 2) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+        (b"fl32" | b"FL32", 4) => |b| f32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        (b"fl64" | b"FL64", 8) => |b|
+            f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+        _ => return Err(format!("unsupported AIFF encoding {} bits '{}'", bits, String::from_utf8_lossy(compression)))
+    };
+
+    if channels == 0 {
+        return Err(String::from("no channels"))
+    }
+
+    let sample_count = (frame_count * channels as usize).min(sound.len() / bytes_per_sample);
+    let samples = sound
+        .chunks_exact(bytes_per_sample)
+        .take(sample_count)
+        .map(decode_one)
+        .collect();
+
+    Ok(Audio { channels, sample_rate, samples, cues: vec![] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bits_per_sample, decode, is_aiff, read_extended};
+
+    /// 44100 as a 80 bits extended float
+    const RATE_44100 : [u8; 10] = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+
+    fn chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(content);
+        if content.len() & 1 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn aiff(kind: &[u8; 4], channels: u16, bits: u16, compression: Option<&[u8; 4]>, data: &[u8]) -> Vec<u8> {
+        let frame_count = data.len() / (channels.max(1) as usize * (bits as usize).div_ceil(8));
+        let mut comm = channels.to_be_bytes().to_vec();
+        comm.extend_from_slice(&(frame_count as u32).to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        comm.extend_from_slice(&RATE_44100);
+        if let Some(compression) = compression {
+            comm.extend_from_slice(compression);
+            comm.extend_from_slice(&[0, 0]);
+        }
+
+        let mut sound = vec![0u8; 8];
+        sound.extend_from_slice(data);
+
+        let mut content = kind.to_vec();
+        content.extend(chunk(b"COMM", &comm));
+        content.extend(chunk(b"ANNO", b"odd"));
+        content.extend(chunk(b"SSND", &sound));
+
+        let mut bytes = b"FORM".to_vec();
+        bytes.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        bytes.extend(content);
+        bytes
+    }
+
+    #[test]
+    fn extended_sample_rates() {
+        assert_eq!(read_extended(&RATE_44100, 0), 44100.0);
+        assert_eq!(read_extended(&[0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0], 0), 48000.0);
+        assert_eq!(read_extended(&[0; 10], 0), 0.0);
+    }
+
+    #[test]
+    fn decode_16_bits_stereo() {
+        let data : Vec<u8> = [16384i16, -16384, 0, 32767].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let bytes = aiff(b"AIFF", 2, 16, None, &data);
+
+        assert!(is_aiff(&bytes));
+        assert_eq!(bits_per_sample(&bytes), Some(16));
+
+        let audio = decode(&bytes).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate, audio.frame_count()), (2, 44100, 2));
+        assert_eq!(audio.samples, vec![0.5, -0.5, 0.0, 32767.0 / 32768.0]);
+    }
+
+    #[test]
+    fn decode_24_bits() {
+        let data = [0x40, 0, 0, 0xC0, 0, 0];
+        let audio = decode(&aiff(b"AIFF", 1, 24, None, &data)).unwrap();
+        assert_eq!(audio.samples, vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn decode_aifc_encodings() {
+        let little : Vec<u8> = [16384i16, -8192].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let audio = decode(&aiff(b"AIFC", 1, 16, Some(b"sowt"), &little)).unwrap();
+        assert_eq!(audio.samples, vec![0.5, -0.25]);
+
+        let float : Vec<u8> = [0.25f32, -1.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let audio = decode(&aiff(b"AIFC", 1, 32, Some(b"fl32"), &float)).unwrap();
+        assert_eq!(audio.samples, vec![0.25, -1.0]);
+
+        assert!(decode(&aiff(b"AIFC", 1, 16, Some(b"ima4"), &[0, 0])).is_err());
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(!is_aiff(b"RIFF\0\0\0\0WAVE"));
+        assert!(decode(b"FORM\0\0\0\x04AIFF").is_err());
+        assert_eq!(bits_per_sample(b"FORM\0\0\0\x04AIFF"), None);
+        assert!(decode(&aiff(b"AIFF", 0, 16, None, &[])).is_err());
+    }
+}
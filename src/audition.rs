@@ -0,0 +1,110 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+
+use glob::glob;
+use m8_file_parser::{ChainStep, Instrument, Note, Song, SongSteps};
+
+use crate::{
+    metadata::is_sample_file,
+    sampler_gen::{device_sample_path, load_base_song, new_sampler, unique_name, write_song},
+    types::{combine, M8FstoErr}
+};
+
+/// Note played to audition the samples, C-4
+const AUDITION_NOTE : u8 = 36;
+
+/// Velocity of the audition notes
+const AUDITION_VELOCITY : u8 = 0x7F;
+
+/// Load a sample in instrument, phrase, chain and song row `slot`,
+/// so scrolling the song rows plays every sample.
+fn load_sample(song: &mut Song, slot: usize, name: &str, sample_path: &str) {
+    let mut sampler = new_sampler(name, sample_path);
+    sampler.number = slot as u8;
+    song.instruments[slot] = Instrument::Sampler(sampler);
+
+    let phrase = &mut song.phrases[slot];
+    phrase.clear();
+    phrase.steps[0].note = Note(AUDITION_NOTE);
+    phrase.steps[0].velocity = AUDITION_VELOCITY;
+    phrase.steps[0].instrument = slot as u8;
+
+    let chain = &mut song.chains[slot];
+    chain.clear();
+    chain.steps[0] = ChainStep { phrase: slot as u8, transpose: 0 };
+
+    let row = slot * SongSteps::TRACK_COUNT;
+    song.song.steps[row .. row + SongSteps::TRACK_COUNT].fill(0xFF);
+    song.song.steps[row] = slot as u8;
+}
+
+/// Name of the nth audition song
+fn song_file_name(name: &str, index: usize, song_count: usize) -> String {
+    if song_count == 1 {
+        format!("{}.m8s", name)
+    } else {
+        format!("{}_{}.m8s", name, index + 1)
+    }
+}
+
+/// Write songs containing a sampler instrument per sample matching
+/// the pattern, 128 samples per song.
+pub fn audition(
+    backup_root: &Path,
+    pattern: &str,
+    base_song: &Path,
+    out: &Path,
+    name: &str) -> Result<(), M8FstoErr> {
+
+    let mut samples = vec![];
+    for entry in glob(pattern)
+        .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) })?
+        .flatten() {
+
+        if is_sample_file(&entry) {
+            samples.push(entry);
+        }
+    }
+    samples.sort();
+
+    if samples.is_empty() {
+        println!("No samples matching {}", pattern);
+        return Ok(())
+    }
+
+    let (data, base) = load_base_song(base_song)?;
+
+    let mut errors = None;
+    let mut device_paths : Vec<(PathBuf, String)> = vec![];
+    for sample in samples {
+        match device_sample_path(backup_root, &sample) {
+            Ok(device_path) => device_paths.push((sample, device_path)),
+            Err(e) => errors = combine(errors, e)
+        }
+    }
+
+    fs::create_dir_all(out)
+        .map_err(|e| M8FstoErr::FolderCreationError { path: out.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    let song_count = device_paths.len().div_ceil(Song::N_INSTRUMENTS);
+    for (index, chunk) in device_paths.chunks(Song::N_INSTRUMENTS).enumerate() {
+        let mut song = base.clone();
+        let mut used_names = HashSet::new();
+
+        for (slot, (sample, device_path)) in chunk.iter().enumerate() {
+            let stem = sample.file_stem().unwrap_or_default().to_string_lossy();
+            let instrument_name = unique_name(&mut used_names, &stem);
+            load_sample(&mut song, slot, &instrument_name, device_path);
+        }
+
+        let out_song = out.join(song_file_name(name, index, song_count));
+        match write_song(&out_song, data.clone(), &song) {
+            Ok(()) => println!("Wrote {} samples in {:?}", chunk.len(), out_song),
+            Err(e) => errors = combine(errors, e)
+        }
+    }
+
+    match errors {
+        None => Ok(()),
+        Some(e) => Err(e)
+    }
+}
@@ -1,93 +1,344 @@
-use std::{collections::{hash_map::Entry, HashMap}, fs, path::{Path, PathBuf}};
-use m8_file_parser::{reader::*, writer::Writer, Instrument};
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
+use m8_file_parser::{reader::*, writer::Writer, Instrument, Mod, Sampler, Song};
 
-use crate::{broken_search::sample_to_absolute_path, types::M8FstoErr};
+use crate::{broken_search::sample_to_absolute_path, m8_path::{check_sample_path, fat_key}, move_samples::normalize_path, query::Query, types::{combine, M8FstoErr}, wav::WavFile};
+
+/// Part of a sample played by a sampler instrument, in M8 parameter
+/// unit: 0x00 is the start of the sample and 0xFF its end.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct PlayRegion {
+    from: u8,
+    to: u8
+}
+
+impl PlayRegion {
+    const FULL : PlayRegion = PlayRegion { from: 0x00, to: 0xFF };
+
+    /// Region covered by the start, loop start and length
+    /// of the instrument.
+    fn of_sampler(sampler: &Sampler) -> PlayRegion {
+        let from = sampler.start.min(sampler.loop_start);
+        let to = sampler.length;
+
+        // weird settings, we don't try to be smart
+        if to <= sampler.start.max(sampler.loop_start) {
+            PlayRegion::FULL
+        } else {
+            PlayRegion { from, to }
+        }
+    }
+
+    /// Frame in a sample corresponding to a parameter value
+    fn frame_of(value: u8, frame_count: usize) -> usize {
+        value as usize * frame_count / 0xFF
+    }
+
+    /// Translate a parameter value of the full sample to the
+    /// same position in the cropped sample.
+    fn rescale(self, value: u8) -> u8 {
+        let span = (self.to - self.from) as f64;
+        let moved = (value.clamp(self.from, self.to) - self.from) as f64;
+        (moved * 255.0 / span).round() as u8
+    }
+
+    /// Rewrite the sampler position parameters after cropping
+    fn apply(self, sampler: &mut Sampler) {
+        sampler.start = self.rescale(sampler.start);
+        sampler.loop_start = self.rescale(sampler.loop_start);
+        sampler.length = self.rescale(sampler.length);
+    }
+}
+
+fn mod_destination(m: &Mod) -> u8 {
+    match m {
+        Mod::AHDEnv(env) => env.dest,
+        Mod::ADSREnv(env) => env.dest,
+        Mod::DrumEnv(env) => env.dest,
+        Mod::LFO(lfo) => lfo.dest,
+        Mod::TrigEnv(env) => env.dest,
+        Mod::TrackingEnv(env) => env.dest,
+    }
+}
+
+/// Sampler commands moving the played region of the sample.
+const REGION_COMMANDS : [&str; 4] = ["STA", "LOP", "LEN", "SLI"];
+
+/// Sampler modulation destinations moving the played region.
+const REGION_DESTINATIONS : [&str; 2] = ["LOOP ST", "LENGTH"];
+
+/// Find the sampler instruments that cannot be cropped, because
+/// they use slices or because their play region is changed while
+/// playing, by modulators, phrase or table commands.
+fn uncroppable_instruments(song: &Song) -> HashSet<usize> {
+    let mut moving = HashSet::new();
+    let all_samplers : Vec<usize> = song.instruments.iter()
+        .enumerate()
+        .filter(|(_, i)| matches!(i, Instrument::Sampler(_)))
+        .map(|(ix, _)| ix)
+        .collect();
+
+    let Some(sampler_ix) = all_samplers.first() else { return moving };
+    let commands = song.instruments[*sampler_ix].instr_command_text(song.version);
+    let is_region_command = |cmd: u8|
+        commands.try_render(cmd).is_some_and(|c| REGION_COMMANDS.contains(&c));
+
+    for (i, instr) in song.instruments.iter().enumerate() {
+        if let Instrument::Sampler(sampler) = instr {
+            let dests = sampler.destination_names(song.version);
+            let modulated = sampler.synth_params.mods.iter().any(|m|
+                dests.get(mod_destination(m) as usize)
+                    .is_some_and(|d| REGION_DESTINATIONS.contains(d)));
+
+            if sampler.slice != 0 || modulated {
+                moving.insert(i);
+            }
+        }
+    }
+
+    // table are bound to instruments with the same number.
+    for ix in &all_samplers {
+        let moved = song.tables[*ix].steps.iter()
+            .any(|s| [s.fx1, s.fx2, s.fx3].iter().any(|fx| is_region_command(fx.command)));
+
+        if moved { moving.insert(*ix); }
+    }
+
+    for phrase in song.phrases.iter() {
+        let mut current = None;
+        for step in phrase.steps.iter() {
+            if (step.instrument as usize) < Song::N_INSTRUMENTS {
+                current = Some(step.instrument as usize);
+            }
+
+            if step.all_fx().iter().any(|fx| is_region_command(fx.command)) {
+                match current {
+                    Some(ix) => { moving.insert(ix); }
+                    // we don't know which instrument is playing, so
+                    // no cropping at all.
+                    None => moving.extend(all_samplers.iter())
+                }
+            }
+        }
+    }
+
+    moving
+}
+
+/// A song loaded in memory, ready to be bundled.
+struct LoadedSong {
+    /// Where the song has been read from
+    path: PathBuf,
+
+    /// Raw song data, needed to rewrite the song
+    data: Vec<u8>,
+
+    /// Parsed song
+    song: Song
+}
+
+/// Samples copied in the `Samples` folder of a bundle, they can
+/// be shared by many songs.
+struct SamplePool {
+    /// The `Samples` folder of the bundle
+    folder: PathBuf,
+
+    /// Already copied samples, from absolute source path and
+    /// copied region to the bundle relative sample path.
+    copied: HashMap<(PathBuf, PlayRegion), String>,
+
+    /// File names already used in the pool
+    used_names: HashSet<String>,
+
+    /// Total size of the copied samples, in bytes
+    size: u64
+}
+
+impl SamplePool {
+    fn new(folder: PathBuf) -> SamplePool {
+        SamplePool {
+            folder,
+            copied: HashMap::new(),
+            used_names: HashSet::new(),
+            size: 0
+        }
+    }
+
+    /// Find a file name in the pool not used by another sample, the
+    /// instrument number is used as a prefix. Names are compared
+    /// ignoring the case, like on the SD card.
+    fn fresh_name(&mut self, instrument: usize, file_name: &str) -> String {
+        let mut out_filename = format!("{}_{}", instrument, file_name);
+        let mut suffix = 1;
+
+        while self.used_names.contains(&fat_key(&out_filename)) {
+            out_filename = format!("{}_{}_{}", instrument, suffix, file_name);
+            suffix += 1;
+        }
+
+        self.used_names.insert(fat_key(&out_filename));
+        out_filename
+    }
+
+    /// Copy the sample into the pool if not already present, and return
+    /// the sample path to be used in the bundled song.
+    fn add(&mut self, instrument: usize, full_sample_path: &Path) -> Result<String, M8FstoErr> {
+        let key = (normalize_path(full_sample_path), PlayRegion::FULL);
+
+        // if we already moved the same sample, we just reuse
+        // the file (deduplication happen)
+        if let Some(prev) = self.copied.get(&key) {
+            return Ok(prev.clone())
+        }
+
+        let file_name = full_sample_path.file_name()
+            .unwrap().to_str().unwrap();
+
+        let out_filename = self.fresh_name(instrument, file_name);
+        let relative_name = format!("Samples/{}", out_filename);
+        check_sample_path(&relative_name, true)?;
+
+        let out_sample_path = self.folder.join(&out_filename);
+
+        self.size += std::fs::copy(full_sample_path, &out_sample_path)
+            .map_err(|e| M8FstoErr::SampleCopyError {
+                path: full_sample_path.to_path_buf(),
+                to: out_sample_path,
+                reason: format!("{:?}", e) })?;
+
+        self.copied.insert(key, relative_name.clone());
+        Ok(relative_name)
+    }
+
+    /// Write only the played region of the sample in the pool. Instruments
+    /// using the same region of the same sample share the cropped file.
+    /// Return None if the sample cannot be cropped.
+    fn add_cropped(&mut self, instrument: usize, full_sample_path: &Path, region: PlayRegion) -> Result<Option<String>, M8FstoErr> {
+        let key = (normalize_path(full_sample_path), region);
+
+        if let Some(prev) = self.copied.get(&key) {
+            return Ok(Some(prev.clone()))
+        }
+
+        // not a sample we can understand, keep it whole
+        let Ok(wav) = WavFile::read(full_sample_path) else { return Ok(None) };
+        let frame_count = wav.frame_count();
+        let cropped = wav.crop(
+            PlayRegion::frame_of(region.from, frame_count),
+            PlayRegion::frame_of(region.to, frame_count));
+
+        let stem = full_sample_path.file_stem()
+            .unwrap().to_str().unwrap();
+
+        let file_name = format!("{}_{:02X}{:02X}.wav", stem, region.from, region.to);
+        let out_filename = self.fresh_name(instrument, &file_name);
+        let relative_name = format!("Samples/{}", out_filename);
+        check_sample_path(&relative_name, true)?;
+
+        self.size += cropped.write(&self.folder.join(&out_filename))?;
+
+        self.copied.insert(key, relative_name.clone());
+        Ok(Some(relative_name))
+    }
+}
+
+fn load_song(song_path: &Path) -> Result<LoadedSong, M8FstoErr> {
+    let data = fs::read(song_path)
+        .map_err(|e|
+            M8FstoErr::CannotReadFile { path: song_path.to_path_buf(), reason: format!("{:?}", e) })?;
 
-fn on_file_blob(backup_root: &Path, song_path: &Path, out_folder: &Path, data: Vec<u8>) -> Result<(), M8FstoErr> {
     let mut reader = Reader::new(data.clone());
-    let mut song = m8_file_parser::Song::read_from_reader(&mut reader)
+    let song = m8_file_parser::Song::read_from_reader(&mut reader)
         .map_err(|e| M8FstoErr::UnparseableM8File {
             path: song_path.to_path_buf(),
             reason: format!("{:?}", e)
         })?;
 
-    // First pass we verify that all the samples exists, before effectively
-    // moving the files.
-    for (i, instr) in song.instruments.iter().enumerate() {
-        match instr {
-            Instrument::Sampler(sampler) => {
-                let full_sample_path =
-                    sample_to_absolute_path(backup_root, song_path, &sampler.sample_path);
+    Ok(LoadedSong { path: song_path.to_path_buf(), data, song })
+}
 
-                if  !full_sample_path.exists() {
-                    return Err(M8FstoErr::MissingSample { instr: i, path: full_sample_path })
-                }
+/// Verify that all the samples of the song exists, before effectively
+/// moving the files.
+fn check_samples(backup_root: &Path, loaded: &LoadedSong) -> Result<(), M8FstoErr> {
+    for (i, instr) in loaded.song.instruments.iter().enumerate() {
+        if let Instrument::Sampler(sampler) = instr {
+            if sampler.sample_path.is_empty() { continue; }
+
+            let full_sample_path =
+                sample_to_absolute_path(backup_root, &loaded.path, &sampler.sample_path);
+
+            if  !full_sample_path.exists() {
+                return Err(M8FstoErr::MissingSample { instr: i, path: full_sample_path })
             }
-            _ => {}
         }
     }
 
-    let out_folder = out_folder.join(&song.name);
-    std::fs::create_dir(&out_folder).map_err(|e|
-        M8FstoErr::FolderCreationError {
-            path: out_folder.clone(),
-            reason: format!("{:?}", e)
-        })?;
+    Ok(())
+}
 
-    let sample_folder_path = out_folder.join("Samples");
-    std::fs::create_dir(&sample_folder_path).map_err(|e|
+/// Size in bytes of all the distinct samples used by a song, what
+/// a standalone bundle of the song would weight.
+fn song_samples_size(backup_root: &Path, loaded: &LoadedSong) -> u64 {
+    let samples : HashSet<_> = loaded.song.instruments
+        .iter()
+        .filter_map(|i| match i {
+            Instrument::Sampler(s) if !s.sample_path.is_empty() =>
+                Some(normalize_path(&sample_to_absolute_path(backup_root, &loaded.path, &s.sample_path))),
+            _ => None
+        })
+        .collect();
+
+    samples.iter()
+        .filter_map(|p| fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+fn create_folder(path: &Path) -> Result<(), M8FstoErr> {
+    std::fs::create_dir(path).map_err(|e|
         M8FstoErr::FolderCreationError {
-            path: out_folder.clone(),
+            path: path.to_path_buf(),
             reason: format!("{:?}", e)
-        })?;
+        })
+}
 
-    let mut samples : HashMap<String, String> = HashMap::new();
+/// Rewrite the sampler instruments of a song to point into the pool,
+/// and write the song in the bundle folder.
+fn bundle_in_pool(backup_root: &Path, loaded: LoadedSong, pool: &mut SamplePool, out_folder: &Path, crop: bool) -> Result<(), M8FstoErr> {
+    let LoadedSong { path: song_path, data, mut song } = loaded;
+    let uncroppable =
+        if crop { uncroppable_instruments(&song) } else { HashSet::new() };
 
     // Let's move the samples and rewrite the sampler instruments
     for (i, instr) in song.instruments.iter_mut().enumerate() {
-        match instr {
-            Instrument::Sampler(sampler) => {
-                let full_sample_path =
-                    sample_to_absolute_path(backup_root, song_path, &sampler.sample_path);
-
-                match samples.entry(sampler.sample_path.clone()) {
-                    // if we already moved the same sample, we just reuse
-                    // the file (deduplication happen)
-                    Entry::Occupied(prev) => {
-                        sampler.sample_path = prev.get().clone()
-                    }
-                    Entry::Vacant(v) => {
-                        let file_name = full_sample_path.file_name()
-                            .unwrap().to_str().unwrap();
-
-                        let out_filename = format!("{}_{}", i, file_name);
-                        let out_sample_path =
-                            sample_folder_path.join(&out_filename);
-
-                        std::fs::copy(&full_sample_path, &out_sample_path)
-                            .map_err(|e| M8FstoErr::SampleCopyError {
-                                path: full_sample_path.clone(),
-                                to: out_sample_path,
-                                reason: format!("{:?}", e) })?;
-
-                        let relative_name = format!("Samples/{}", out_filename);
-                        sampler.sample_path = relative_name.clone();
-                        v.insert(relative_name);
-                    }
+        if let Instrument::Sampler(sampler) = instr {
+            if sampler.sample_path.is_empty() { continue; }
+
+            let full_sample_path =
+                sample_to_absolute_path(backup_root, &song_path, &sampler.sample_path);
+
+            let region = PlayRegion::of_sampler(sampler);
+            if !crop || region == PlayRegion::FULL || uncroppable.contains(&i) {
+                sampler.sample_path = pool.add(i, &full_sample_path)?;
+                continue;
+            }
+
+            match pool.add_cropped(i, &full_sample_path, region)? {
+                None => sampler.sample_path = pool.add(i, &full_sample_path)?,
+                Some(cropped_path) => {
+                    sampler.sample_path = cropped_path;
+                    region.apply(sampler);
                 }
             }
-            _ => {}
         }
     }
 
-
     let out_song_name =
         out_folder.join(song_path.file_name().unwrap());
 
     let mut writer = Writer::new(data);
     song.write(&mut writer)
         .map_err(|reason|
-            M8FstoErr::SongSerializationError { 
+            M8FstoErr::SongSerializationError {
                 destination: format!("{:?}", &out_song_name),
                 reason
             })?;
@@ -98,24 +349,162 @@ fn on_file_blob(backup_root: &Path, song_path: &Path, out_folder: &Path, data: V
                 destination: format!("{:?}", out_song_name),
                 reason: format!("{:?}", reason)
             })?;
-    
+
     Ok(())
 }
 
-/// Try to list sample of a given path
-pub fn bundle_song(cwd: &Path, path : &str, out_folder: &Option<String>) -> Result<(), M8FstoErr> {
-    let file_blob = fs::read(path)
-        .map_err(|e|
-            M8FstoErr::CannotReadFile { path: PathBuf::from(path), reason: format!("{:?}", e) })?;
+/// Display a byte count in a human readable way
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS : [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Expand song arguments, each one can be a song path or a glob
+/// pattern.
+fn expand_songs(songs: &[String]) -> Result<Vec<PathBuf>, M8FstoErr> {
+    let mut found = vec![];
+
+    for song in songs {
+        let as_path = PathBuf::from(song);
+        if as_path.is_file() {
+            found.push(as_path);
+            continue;
+        }
+
+        let matches = glob::glob(song)
+            .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) })?;
+
+        let before = found.len();
+        found.extend(matches.filter_map(|m| m.ok()).filter(|p| p.is_file()));
+
+        if found.len() == before {
+            return Err(M8FstoErr::InvalidPath { reason: format!("no song matching {}", song) })
+        }
+    }
+
+    Ok(found)
+}
+
+/// Load the songs, only keeping the ones matching the query if any.
+/// Paths are matched from the backup root.
+fn load_songs(backup_root: &Path, song_paths: &[PathBuf], query: &Option<Query>) -> Result<Vec<LoadedSong>, M8FstoErr> {
+    let mut loaded = vec![];
+    let mut errors = None;
 
-    let as_path = Path::new(path);
+    for song_path in song_paths {
+        match load_song(song_path) {
+            Err(e) => errors = combine(errors, e),
+            Ok(song) => {
+                let relative = song_path.strip_prefix(backup_root).unwrap_or(song_path);
+                if query.as_ref().is_none_or(|q| q.matches(relative, &song.song)) {
+                    loaded.push(song);
+                }
+            }
+        }
+    }
+
+    match errors {
+        Some(errs) => Err(errs),
+        None if loaded.is_empty() =>
+            Err(M8FstoErr::InvalidPath { reason: String::from("no song matching the query") }),
+        None => Ok(loaded)
+    }
+}
+
+/// Bundle many songs in a single set folder, all the songs sharing
+/// the same sample pool.
+fn bundle_set(backup_root: &Path, loaded: Vec<LoadedSong>, set_folder: &Path, crop: bool) -> Result<(), M8FstoErr> {
+    let mut errors = None;
+    let mut out_names = HashSet::new();
+
+    for song in loaded.iter() {
+        if let Err(e) = check_samples(backup_root, song) {
+            errors = combine(errors, e);
+        }
+
+        let file_name = song.path.file_name().unwrap();
+        if !out_names.insert(file_name.to_os_string()) {
+            errors = combine(errors, M8FstoErr::InvalidPath {
+                reason: format!("two songs named {:?} in the set", file_name)
+            });
+        }
+    }
+
+    if let Some(errs) = errors {
+        return Err(errs)
+    }
+
+    let separate_size : u64 =
+        loaded.iter().map(|s| song_samples_size(backup_root, s)).sum();
+
+    create_folder(set_folder)?;
+    let sample_folder_path = set_folder.join("Samples");
+    create_folder(&sample_folder_path)?;
+
+    let song_count = loaded.len();
+    let mut pool = SamplePool::new(sample_folder_path);
+    for song in loaded {
+        bundle_in_pool(backup_root, song, &mut pool, set_folder, crop)?;
+    }
+
+    println!("Bundled {} songs in {:?}", song_count, set_folder);
+    println!("  shared sample pool : {} samples, {}", pool.copied.len(), human_size(pool.size));
+    println!("  separate bundles   : {}", human_size(separate_size));
+
+    Ok(())
+}
+
+/// Bundle one or many songs, if many songs are given, they are
+/// bundled together in a set folder. With `crop`, only the played
+/// part of the samples are written in the bundle. With a query, only the
+/// matching songs are bundled.
+pub fn bundle_song(
+    cwd: &Path,
+    songs: &[String],
+    set_name: &Option<String>,
+    out_folder: &Option<String>,
+    crop: bool,
+    query: &Option<String>) -> Result<(), M8FstoErr> {
+
+    let query = query.as_deref().map(Query::parse).transpose()?;
+    let mut loaded = load_songs(cwd, &expand_songs(songs)?, &query)?;
 
     let out_folder =
         out_folder
             .clone()
             .map_or_else(
                 || cwd.to_path_buf().join("Bundles"),
-                |e| PathBuf::from(e));
+                PathBuf::from);
+
+    match (set_name, loaded.len()) {
+        (None, 1) => {
+            let loaded = loaded.remove(0);
+            check_samples(cwd, &loaded)?;
+
+            let out_folder = out_folder.join(&loaded.song.name);
+            create_folder(&out_folder)?;
+
+            let sample_folder_path = out_folder.join("Samples");
+            create_folder(&sample_folder_path)?;
 
-    on_file_blob(cwd, as_path, &out_folder, file_blob)
-}
\ No newline at end of file
+            let mut pool = SamplePool::new(sample_folder_path);
+            bundle_in_pool(cwd, loaded, &mut pool, &out_folder, crop)
+        }
+        (set_name, _) => {
+            let set_name = set_name.clone().unwrap_or_else(|| String::from("SET"));
+            bundle_set(cwd, loaded, &out_folder.join(set_name), crop)
+        }
+    }
+}
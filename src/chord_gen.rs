@@ -1,7 +1,9 @@
-use m8_file_parser::writer::Writer;
-use m8_file_parser::{ AHDEnv, FMWave, FmAlgo, HyperSynth, Instrument, InstrumentWithEq, LfoShape, LimitType, Mod, Operator, SynthParams, Table, Version, LFO };
+use std::path::Path;
+
+use m8_file_parser::{ FMWave, FmAlgo, HyperSynth, Instrument, Operator };
 use m8_file_parser::FMSynth;
 
+use crate::sampler_gen::{default_synth_params, write_instrument};
 use crate::types::M8FstoErr;
 
 #[derive(Clone)]
@@ -37,7 +39,7 @@ impl Chord {
 
     fn make_op(&self, ix: usize) -> Operator {
         let ofs = self.offsets[ix];
-        let freq : f64 = (2 as f64).powf((ofs as f64) / 12.0);
+        let freq : f64 = 2_f64.powf((ofs as f64) / 12.0);
         let fine = (freq - ((freq as i64) as f64)) * 100.0;
 
         Operator {
@@ -64,7 +66,7 @@ impl Chord {
         let mut ofs = self.offsets.clone();
         let mut mutate_cursor = 8;
 
-        for i in 0 .. ofs.len() {
+        for chord in chords.iter_mut().take(ofs.len()) {
             if mutate_cursor >= ofs.len() {
                 mutate_cursor = 0;
             } else {
@@ -73,13 +75,13 @@ impl Chord {
             }
 
             let mut mask = 0xC0;
-            let max = (chords[i].offsets.len() / ofs.len()) * ofs.len();
+            let max = (chord.offsets.len() / ofs.len()) * ofs.len();
             for write_cursor in 0 .. max {
-                chords[i].offsets[write_cursor] = ofs[write_cursor % ofs.len()] as u8;
-                mask = mask | (1 << write_cursor);
+                chord.offsets[write_cursor] = ofs[write_cursor % ofs.len()] as u8;
+                mask |= 1 << write_cursor;
             }
 
-            chords[i].mask = mask
+            chord.mask = mask
         }
 
         HyperSynth {
@@ -87,7 +89,7 @@ impl Chord {
             name: self.name.clone(),
             transpose: true,
             table_tick: 1,
-            synth_params: Self::make_synth_param(),
+            synth_params: default_synth_params(),
             scale: 0x00,
             default_chord,
             shift: 0x80,
@@ -98,48 +100,6 @@ impl Chord {
         }
     }
 
-    fn make_synth_param() -> SynthParams {
-        let ahd = AHDEnv {
-            dest: 0,
-            amount: 0xFF,
-            attack: 0,
-            hold: 0,
-            decay: 0x80,
-        };
-
-        let lfo = LFO {
-            shape: LfoShape::TRI,
-            dest: 0,
-            trigger_mode: m8_file_parser::LfoTriggerMode::FREE,
-            freq: 0x10,
-            amount: 0xFF,
-            retrigger: 0,
-        };
-
-        SynthParams {
-            volume: 0x0,
-            pitch: 0,
-            fine_tune: 0x80,
-            filter_type: 0,
-            filter_cutoff: 0xFF,
-            filter_res: 0x0,
-            amp: 0,
-            limit: LimitType(0),
-            mixer_pan: 0x80,
-            mixer_dry: 0xC0,
-            mixer_mfx: 0,
-            mixer_delay: 0,
-            mixer_reverb: 0x00,
-            associated_eq: 0x80,
-            mods: [
-                Mod::AHDEnv(ahd.clone()),
-                Mod::AHDEnv(ahd),
-                Mod::LFO(lfo.clone()),
-                Mod::LFO(lfo),
-            ],
-        }
-    }
-
     pub fn as_fm(&self, inversion: u8) -> FMSynth {
         let mk_op = |i| {
             if self.offsets.len() > i {
@@ -174,7 +134,7 @@ impl Chord {
                 },
             transpose: true,
             table_tick: 1,
-            synth_params: Self::make_synth_param(),
+            synth_params: default_synth_params(),
             algo,
             operators,
             mod1: 0,
@@ -205,19 +165,6 @@ fn build_chords() -> Vec<Chord> {
     ]
 }
 
-fn wrap(synth: Instrument) -> InstrumentWithEq {
-    let ver = Version {
-        major: 4, minor: 2, patch: 0
-    };
-
-    InstrumentWithEq {
-        instrument: synth,
-        table: Table::default_ver(ver),
-        eq: None,
-        version: ver,
-    }
-}
-
 pub fn generate() -> Result<(), M8FstoErr> {
 
     for chord in &build_chords() {
@@ -230,48 +177,19 @@ pub fn generate() -> Result<(), M8FstoErr> {
                 reason: format!("{}", err)
         })?;
 
-        let instrument =
-            wrap(Instrument::FMSynth(chord.as_fm(0)));
-
-        let mut w = Writer::new_instrument_writer(false);
         let instr_name = format!("{}/{}.m8i", &folder_prefix, &chord.name);
-        instrument.write(&mut w);
-
-        std::fs::write(&instr_name, &w.finish())
-            .map_err(|err|
-                M8FstoErr::SongSerializationError {
-                    destination: instr_name.clone(),
-                    reason: format!("{}", err)
-                })?;
+        write_instrument(Path::new(&instr_name), Instrument::FMSynth(chord.as_fm(0)))?;
 
         let mut cchord = chord.clone();
         for inversion in 0 .. chord.len() - 1 {
             let instr_name = format!("{}/{}_INV{}.m8i", &folder_prefix, &chord.name, inversion);
             cchord.offsets[inversion] += 12;
             let as_fm = cchord.as_fm((inversion + 1) as u8);
-            let inv_instr = wrap(Instrument::FMSynth(as_fm));
-            let mut inv_w = Writer::new_instrument_writer(false);
-            inv_instr.write(&mut inv_w);
-            std::fs::write(&instr_name, &inv_w.finish())
-                .map_err(|err|
-                    M8FstoErr::SongSerializationError {
-                        destination: instr_name.clone(),
-                        reason: format!("{}", err)
-                    })?;
+            write_instrument(Path::new(&instr_name), Instrument::FMSynth(as_fm))?;
         }
 
-        let hs_chord =
-            wrap(Instrument::HyperSynth(chord.as_hypersynth()));
         let hs_instr_name = format!("{}/{}_HS.m8i", &folder_prefix, &chord.name);
-
-        let mut hs_w = Writer::new_instrument_writer(false);
-        hs_chord.write(&mut hs_w);
-        std::fs::write(&hs_instr_name, &hs_w.finish())
-            .map_err(|err|
-                M8FstoErr::SongSerializationError {
-                    destination: hs_instr_name.clone(),
-                    reason: format!("{}", err)
-                })?;
+        write_instrument(Path::new(&hs_instr_name), Instrument::HyperSynth(chord.as_hypersynth()))?;
     }
 
     Ok(())
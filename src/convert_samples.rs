@@ -0,0 +1,297 @@
+use std::{collections::BTreeSet, fs, path::{Path, PathBuf}};
+use m8_file_parser::Instrument;
+
+use crate::{
+    aiff,
+    broken_search::{is_sample_absolute, sample_to_absolute_path},
+    library::Library,
+    move_samples::{self, Swap},
+    types::{combine, FlagBag, M8FstoErr},
+    wav::{Audio, WavFile, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM}
+};
+
+/// Folder, in the backup root, where the original samples are kept
+/// after conversion.
+pub const QUARANTINE_FOLDER : &str = "Quarantine";
+
+/// Above this sample rate, samples are considered wasteful
+const MAX_SAMPLE_RATE : u32 = 48000;
+
+/// Rate used when resampling wasteful samples
+const DEFAULT_SAMPLE_RATE : u32 = 44100;
+
+/// How the samples must be converted
+pub struct ConvertOptions {
+    /// Bit depth of the converted samples, if not set 16 bits
+    /// samples stay in 16 bits, and others are written in 24 bits.
+    pub bits: Option<u16>,
+
+    /// Downmix stereo samples
+    pub mono: bool,
+
+    /// Sample rate for all the samples
+    pub rate: Option<u32>
+}
+
+/// A sample to be converted
+struct Conversion {
+    /// Sample path as written in the songs
+    m8_path: String,
+
+    /// Sample path to write in the songs after conversion
+    out_m8_path: String,
+
+    /// Where is the sample on disk
+    full_path: PathBuf,
+
+    /// Where the converted sample will be written
+    out_full_path: PathBuf,
+
+    /// Why the sample must be converted
+    reasons: Vec<String>
+}
+
+/// Gather the absolute sample paths used by all the songs under the root.
+/// Relative sample paths are used by bundles and are ignored.
+fn referenced_samples(root: &Path, excludes: &[String]) -> (BTreeSet<(String, PathBuf)>, Option<M8FstoErr>) {
+    let mut samples = BTreeSet::new();
+
+    let library = match Library::new(root).exclude(excludes) {
+        Ok(library) => library.songs_only(),
+        Err(e) => return (samples, Some(e))
+    };
+
+    let walked = library.walk(|file| {
+        for (_, instr) in file.instruments() {
+            if let Instrument::Sampler(sampler) = instr {
+                if !sampler.sample_path.is_empty() && is_sample_absolute(&sampler.sample_path) {
+                    let full = sample_to_absolute_path(root, &file.path, &sampler.sample_path);
+                    samples.insert((sampler.sample_path.clone(), full));
+                }
+            }
+        }
+        Ok(())
+    });
+
+    (samples, walked.err())
+}
+
+/// Load a sample as WAV or AIFF
+pub(crate) fn decode_sample(bytes: &[u8]) -> Result<Audio, String> {
+    if aiff::is_aiff(bytes) {
+        aiff::decode(bytes)
+    } else {
+        WavFile::parse(bytes)?.decode()
+    }
+}
+
+/// Check if a sample needs a conversion, and why.
+fn conversion_reasons(options: &ConvertOptions, bytes: &[u8]) -> Result<Vec<String>, String> {
+    let mut reasons = vec![];
+
+    let (channels, sample_rate) = if aiff::is_aiff(bytes) {
+        let audio = aiff::decode(bytes)?;
+        reasons.push(String::from("AIFF"));
+        (audio.channels, audio.sample_rate)
+    } else {
+        let wav = WavFile::parse(bytes)?;
+        match (wav.sample_format(), wav.bits_per_sample) {
+            (WAVE_FORMAT_PCM, 8 | 16 | 24) => {}
+            (WAVE_FORMAT_PCM, 32) => reasons.push(String::from("32-bit integer")),
+            (WAVE_FORMAT_IEEE_FLOAT, 32 | 64) => reasons.push(wav.format_name()),
+            _ => return Err(format!("cannot convert {}", wav.format_name()))
+        }
+        (wav.channels, wav.sample_rate)
+    };
+
+    match options.rate {
+        Some(rate) if rate != sample_rate =>
+            reasons.push(format!("{} Hz", sample_rate)),
+        None if sample_rate > MAX_SAMPLE_RATE =>
+            reasons.push(format!("{} Hz", sample_rate)),
+        _ => {}
+    }
+
+    if options.mono && channels > 1 {
+        reasons.push(String::from("stereo"));
+    }
+
+    Ok(reasons)
+}
+
+fn plan_conversion(options: &ConvertOptions, m8_path: &str, full_path: &Path) -> Result<Option<Conversion>, M8FstoErr> {
+    let bytes = fs::read(full_path)
+        .map_err(|e| M8FstoErr::CannotReadFile { path: full_path.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    let reasons = conversion_reasons(options, &bytes)
+        .map_err(|reason| M8FstoErr::UnparseableSample { path: full_path.to_path_buf(), reason })?;
+
+    if reasons.is_empty() {
+        return Ok(None)
+    }
+
+    let out_m8_path = PathBuf::from(m8_path)
+        .with_extension("wav")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let out_full_path = full_path.with_extension("wav");
+    if out_full_path != full_path && out_full_path.exists() {
+        return Err(M8FstoErr::InvalidPath {
+            reason: format!("cannot convert {:?}, {:?} already exists", full_path, out_full_path)
+        })
+    }
+
+    Ok(Some(Conversion {
+        m8_path: m8_path.to_string(),
+        out_m8_path,
+        full_path: full_path.to_path_buf(),
+        out_full_path,
+        reasons
+    }))
+}
+
+/// Where the original sample is kept, mirroring the backup hierarchy
+fn quarantine_path(root: &Path, conversion: &Conversion) -> PathBuf {
+    root.join(QUARANTINE_FOLDER)
+        .join(conversion.m8_path.trim_start_matches('/'))
+}
+
+/// Where the converted sample is written until it replaces the original,
+/// in the quarantine to be purged with it after an undo.
+fn converted_path(root: &Path, conversion: &Conversion) -> PathBuf {
+    let mut converted = root.join(QUARANTINE_FOLDER)
+        .join(conversion.out_m8_path.trim_start_matches('/'))
+        .into_os_string();
+    converted.push(".m8fsto");
+    PathBuf::from(converted)
+}
+
+/// Write the converted sample aside, it is moved in place with
+/// the other changes, so the sample is never missing if something fails.
+fn convert(root: &Path, options: &ConvertOptions, conversion: &Conversion) -> Result<(), M8FstoErr> {
+    let bytes = fs::read(&conversion.full_path)
+        .map_err(|e| M8FstoErr::CannotReadFile { path: conversion.full_path.clone(), reason: format!("{:?}", e) })?;
+
+    let source_bits = WavFile::parse(&bytes)
+        .map(|w| w.bits_per_sample)
+        .ok()
+        .or_else(|| aiff::bits_per_sample(&bytes))
+        .unwrap_or(24);
+    let mut audio = decode_sample(&bytes)
+        .map_err(|reason| M8FstoErr::UnparseableSample { path: conversion.full_path.clone(), reason })?;
+
+    if options.mono && audio.channels > 1 {
+        audio = audio.to_mono();
+    }
+
+    let rate = match options.rate {
+        Some(rate) => rate,
+        None if audio.sample_rate > MAX_SAMPLE_RATE => DEFAULT_SAMPLE_RATE,
+        None => audio.sample_rate
+    };
+    audio = audio.resample(rate);
+
+    let bits = options.bits.unwrap_or(if source_bits <= 16 { 16 } else { 24 });
+    let converted = converted_path(root, conversion);
+    let folder = converted.parent().unwrap();
+    fs::create_dir_all(folder)
+        .map_err(|e| M8FstoErr::FolderCreationError { path: folder.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    audio.to_pcm_wav(bits).write(&converted)?;
+    Ok(())
+}
+
+/// Delete the quarantine folder, once the converted samples have
+/// been checked.
+fn purge_quarantine(root: &Path, flags: &FlagBag) -> Result<(), M8FstoErr> {
+    let quarantine = root.join(QUARANTINE_FOLDER);
+    if !quarantine.exists() {
+        println!("No quarantine folder, nothing to do!");
+        return Ok(())
+    }
+
+    println!("Removing {:?}", quarantine);
+    if flags.dry_run {
+        return Ok(())
+    }
+
+    fs::remove_dir_all(&quarantine)
+        .map_err(|e| M8FstoErr::FileRemovalFailure { path: quarantine, reason: format!("{:?}", e) })
+}
+
+/// Convert samples used by songs to M8 friendly formats, and rewrite
+/// the songs if the sample path changed. The songs are prepared before
+/// converting anything, then the songs are written and the samples
+/// replaced in a single transaction, which can be undone.
+pub fn convert_samples(root: &Path, flags: FlagBag, options: ConvertOptions, confirm: bool, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let root = move_samples::normalize_path(root);
+
+    if confirm {
+        return purge_quarantine(&root, &flags)
+    }
+
+    let library = Library::new(&root).exclude(excludes)?;
+    let (samples, mut errors) = referenced_samples(&root, excludes);
+
+    let mut conversions = vec![];
+    for (m8_path, full_path) in samples {
+        if !full_path.exists() {
+            continue;
+        }
+
+        match plan_conversion(&options, &m8_path, &full_path) {
+            Ok(None) => {}
+            Ok(Some(conversion)) if quarantine_path(&root, &conversion).exists() => {
+                errors = combine(errors, M8FstoErr::InvalidPath {
+                    reason: format!("cannot convert {:?}, already in quarantine", conversion.full_path)
+                });
+            }
+            Ok(Some(conversion)) => {
+                println!(" * \"{}\" -> \"{}\" ({})",
+                    conversion.m8_path,
+                    conversion.out_m8_path,
+                    conversion.reasons.join(", "));
+                conversions.push(conversion);
+            }
+            Err(e) => errors = combine(errors, e)
+        }
+    }
+
+    let swaps : Vec<Swap> = conversions.iter()
+        .filter(|c| c.out_m8_path != c.m8_path)
+        .map(|c| Swap::File { from: c.m8_path.clone(), to: c.out_m8_path.clone() })
+        .collect();
+
+    let staged = move_samples::stage(&flags, &root, &library, &swaps);
+    let staging_errors = move_samples::staged_errors(&staged);
+    if flags.dry_run || conversions.is_empty() || (staging_errors.is_some() && !flags.force) {
+        return staging_errors.into_iter().fold(errors, combine).map_or(Ok(()), Err)
+    }
+
+    let mut renames = vec![];
+    for conversion in conversions.iter() {
+        if let Err(e) = convert(&root, &options, conversion) {
+            for conversion in conversions.iter() {
+                let _ = fs::remove_file(converted_path(&root, conversion));
+            }
+            return Err(e)
+        }
+
+        renames.push((conversion.full_path.clone(), quarantine_path(&root, conversion)));
+        renames.push((converted_path(&root, conversion), conversion.out_full_path.clone()));
+    }
+
+    let applied = move_samples::apply_staged(&flags, &root, String::from("convert-samples"), staged, renames);
+    for conversion in conversions.iter() {
+        let _ = fs::remove_file(converted_path(&root, conversion));
+    }
+
+    match (applied, errors) {
+        (Ok(()), None) => Ok(()),
+        (Ok(()), Some(errs)) => Err(errs),
+        (Err(e), None) => Err(e),
+        (Err(e), Some(errs)) => Err(errs.combine(e))
+    }
+}
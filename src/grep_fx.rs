@@ -0,0 +1,102 @@
+use std::path::Path;
+use m8_file_parser::{CommandPack, FxCommands, Song, Version, FX};
+
+use crate::{library::{Library, M8Content, M8File}, types::M8FstoErr};
+
+/// Parse a command value, written in hexadecimal like on the device
+pub fn parse_fx_value(value: &str) -> Result<u8, String> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u8::from_str_radix(digits, 16)
+        .map_err(|_| format!("'{}' is not an hexadecimal value between 00 and FF", value))
+}
+
+/// Name of the command as displayed by the device, sequencer and mixer
+/// commands first, then the commands of the playing instrument.
+fn command_name(fx: FX, commands: FxCommands, pack: CommandPack) -> String {
+    match commands.try_render(fx.command) {
+        Some(name) => name.to_string(),
+        None if pack.accepts(fx.command) => match pack.try_render(fx.command) {
+            Some(name) => name.to_string(),
+            None => format!("I{:02X}", fx.command - 0x80)
+        },
+        None => format!("?{:02X}", fx.command)
+    }
+}
+
+/// Searched command, with an optional value
+struct FxQuery<'a> {
+    command: &'a str,
+    value: Option<u8>
+}
+
+impl FxQuery<'_> {
+    /// Print the columns of a step using the command
+    fn print_step(&self, location: &str, row: usize, fxs: [FX; 3], ver: Version, pack: CommandPack) {
+        let commands = FX::fx_command_names(ver);
+        for (column, fx) in fxs.iter().enumerate() {
+            if fx.is_empty() || self.value.is_some_and(|v| v != fx.value) {
+                continue;
+            }
+
+            let name = command_name(*fx, commands, pack);
+            if name.eq_ignore_ascii_case(self.command) {
+                println!("{}:{:X} FX{} {}{:02X}", location, row, column + 1, name, fx.value);
+            }
+        }
+    }
+
+    fn on_song(&self, path: &str, song: &Song) {
+        for (ix, phrase) in song.phrases.iter().enumerate() {
+            let location = format!("{}:PHRASE {:02X}", path, ix);
+
+            // like on the device, a step without instrument uses
+            // the commands of the previous one.
+            let mut pack = CommandPack::default();
+            for (row, step) in phrase.steps.iter().enumerate() {
+                if (step.instrument as usize) < Song::N_INSTRUMENTS {
+                    pack = song.instruments[step.instrument as usize].instr_command_text(song.version);
+                }
+                self.print_step(&location, row, step.all_fx(), song.version, pack);
+            }
+        }
+
+        // tables are bound to the instrument with the same number
+        for (ix, table) in song.tables.iter().enumerate() {
+            let location = format!("{}:TABLE {:02X}", path, ix);
+            let pack = song.instruments.get(ix)
+                .map_or_else(CommandPack::default, |i| i.instr_command_text(song.version));
+
+            for (row, step) in table.steps.iter().enumerate() {
+                self.print_step(&location, row, [step.fx1, step.fx2, step.fx3], song.version, pack);
+            }
+        }
+    }
+
+    fn on_file(&self, cwd: &Path, file: &M8File) {
+        let path = file.relative_path(cwd).display().to_string();
+        match &file.content {
+            M8Content::Song(song) => self.on_song(&path, song),
+            M8Content::Instrument(instr_eq) => {
+                let location = format!("{}:TABLE 00", path);
+                let pack = instr_eq.instrument.instr_command_text(instr_eq.version);
+                for (row, step) in instr_eq.table.steps.iter().enumerate() {
+                    self.print_step(&location, row, [step.fx1, step.fx2, step.fx3], instr_eq.version, pack);
+                }
+            }
+        }
+    }
+}
+
+/// Find the phrase and table steps using an FX command, with an
+/// optional value, in the songs and instrument files of a given path.
+pub fn grep_fx(cwd: &Path, command: &str, value: Option<u8>, path : &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let query = FxQuery { command, value };
+
+    Library::new(cwd)
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            query.on_file(cwd, &file);
+            Ok(())
+        })
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+use glob::{MatchOptions, Pattern};
+use m8_file_parser::Instrument;
+
+use crate::{
+    library::{Library, M8File},
+    metadata::{instrument_kind, instrument_name, midi_channel},
+    types::M8FstoErr
+};
+
+/// Names and shapes are matched ignoring the case
+const MATCH_OPTIONS : MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false
+};
+
+/// Properties searched, an instrument must match all of the set ones
+pub struct InstrumentQuery {
+    /// One of `metadata::INSTRUMENT_KINDS`
+    pub kind: Option<String>,
+
+    /// Glob pattern on the instrument name
+    pub name: Option<String>,
+
+    /// MIDI channel of MIDI out and external instruments
+    pub channel: Option<u8>,
+
+    /// Glob pattern on the shape of macrosynth and wavsynth instruments
+    pub shape: Option<String>,
+
+    /// Algorithm number of FM synth instruments
+    pub algo: Option<u8>
+}
+
+/// Query with its compiled patterns
+struct Matcher<'a> {
+    query: &'a InstrumentQuery,
+    name: Option<Pattern>,
+    shape: Option<Pattern>
+}
+
+fn compile(pattern: &Option<String>) -> Result<Option<Pattern>, M8FstoErr> {
+    pattern.as_ref()
+        .map(|p| Pattern::new(p)
+            .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) }))
+        .transpose()
+}
+
+fn shape(instr: &Instrument) -> Option<String> {
+    match instr {
+        Instrument::MacroSynth(ms) => Some(format!("{:?}", ms.shape)),
+        Instrument::WavSynth(ws) => Some(format!("{:?}", ws.shape)),
+        _ => None
+    }
+}
+
+impl Matcher<'_> {
+    fn matches(&self, instr: &Instrument) -> bool {
+        if instr.is_empty() {
+            return false
+        }
+
+        let query = self.query;
+        let kind_ok = query.kind.as_ref()
+            .is_none_or(|k| k.eq_ignore_ascii_case(instrument_kind(instr)));
+
+        let name_ok = self.name.as_ref()
+            .is_none_or(|p| p.matches_with(instrument_name(instr), MATCH_OPTIONS));
+
+        let channel_ok = query.channel
+            .is_none_or(|c| midi_channel(instr) == Some(c));
+
+        let shape_ok = self.shape.as_ref()
+            .is_none_or(|p| shape(instr).is_some_and(|s| p.matches_with(&s, MATCH_OPTIONS)));
+
+        let algo_ok = query.algo.is_none_or(|a| match instr {
+            Instrument::FMSynth(fm) => fm.algo.id() == a,
+            _ => false
+        });
+
+        kind_ok && name_ok && channel_ok && shape_ok && algo_ok
+    }
+
+    fn on_file(&self, cwd: &Path, file: &M8File) {
+        for (i, instr) in file.instruments() {
+            if self.matches(instr) {
+                println!("{}:{:02X} {} : {}",
+                    file.relative_path(cwd).display(),
+                    i,
+                    instrument_name(instr),
+                    instrument_kind(instr));
+            }
+        }
+    }
+}
+
+/// Find the instruments matching the query in the songs and instrument
+/// files of a given path.
+pub fn grep_instrument(cwd: &Path, query: &InstrumentQuery, path : &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let matcher = Matcher {
+        query,
+        name: compile(&query.name)?,
+        shape: compile(&query.shape)?
+    };
+
+    Library::new(cwd)
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            matcher.on_file(cwd, &file);
+            Ok(())
+        })
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+use m8_file_parser::{Note, Phrase, Song};
+
+use crate::{library::{Library, M8Content, M8File}, types::M8FstoErr};
+
+/// Note names, as displayed by the device
+const NOTE_NAMES : [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Notes above are note offs
+const NOTE_OFF : u8 = 0x80;
+
+/// Melody searched in the phrases
+pub enum Melody {
+    /// Exact notes
+    Notes(Vec<u8>),
+
+    /// Semitones between the successive notes, at any pitch
+    Intervals(Vec<i16>)
+}
+
+fn invalid(melody: &str, reason: &str) -> M8FstoErr {
+    M8FstoErr::InvalidArgument { argument: melody.to_string(), reason: reason.to_string() }
+}
+
+/// Parse a note written like on the device (`C-4`, `D#4`), the
+/// separator can be omitted (`C4`).
+fn parse_note(text: &str) -> Option<u8> {
+    let upper = text.to_ascii_uppercase();
+    let (name, octave) = match upper.get(1..2) {
+        Some("#") => upper.split_at(2),
+        Some("-") => (&upper[..1], &upper[2..]),
+        _ => upper.split_at_checked(1)?
+    };
+
+    if octave.is_empty() || !octave.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+
+    let semitone = NOTE_NAMES.iter().position(|n| *n == name)?;
+    let octave = u8::from_str_radix(octave, 16).ok()?.checked_sub(1)?;
+    let note = octave as usize * 12 + semitone;
+
+    (note < NOTE_OFF as usize).then_some(note as u8)
+}
+
+fn parse_interval(text: &str) -> Option<i16> {
+    if text != "0" && !text.starts_with('+') && !text.starts_with('-') {
+        return None
+    }
+    text.parse().ok()
+}
+
+impl Melody {
+    /// Parse notes or intervals (`+3 -2`), with `transposed` notes are
+    /// searched at any pitch.
+    pub fn parse(items: &[String], transposed: bool) -> Result<Melody, M8FstoErr> {
+        if items.iter().all(|i| parse_interval(i).is_some()) {
+            return Ok(Melody::Intervals(items.iter().filter_map(|i| parse_interval(i)).collect()))
+        }
+
+        let notes = items.iter()
+            .map(|i| parse_note(i).ok_or_else(|| invalid(i, "neither a note (C-4, D#4) nor an interval (+3, -2)")))
+            .collect::<Result<Vec<u8>, M8FstoErr>>()?;
+
+        if !transposed {
+            return Ok(Melody::Notes(notes))
+        }
+
+        if notes.len() < 2 {
+            return Err(invalid(&items.join(" "), "at least two notes are needed to search a transposed melody"))
+        }
+
+        Ok(Melody::Intervals(notes.windows(2).map(|w| w[1] as i16 - w[0] as i16).collect()))
+    }
+
+    /// Number of notes of the melody
+    fn len(&self) -> usize {
+        match self {
+            Melody::Notes(notes) => notes.len(),
+            Melody::Intervals(intervals) => intervals.len() + 1
+        }
+    }
+
+    fn matches(&self, notes: &[u8]) -> bool {
+        match self {
+            Melody::Notes(searched) => searched == notes,
+            Melody::Intervals(intervals) => notes.windows(2)
+                .map(|w| w[1] as i16 - w[0] as i16)
+                .eq(intervals.iter().copied())
+        }
+    }
+
+    /// Steps of the phrase where the melody starts, along with the
+    /// found notes. Empty steps and note offs are skipped.
+    fn find(&self, phrase: &Phrase) -> Vec<(usize, Vec<u8>)> {
+        let played : Vec<(usize, u8)> = phrase.steps.iter()
+            .enumerate()
+            .filter(|(_, s)| !s.note.is_empty() && s.note.0 < NOTE_OFF)
+            .map(|(row, s)| (row, s.note.0))
+            .collect();
+
+        played.windows(self.len())
+            .filter_map(|w| {
+                let notes : Vec<u8> = w.iter().map(|(_, n)| *n).collect();
+                self.matches(&notes).then(|| (w[0].0, notes))
+            })
+            .collect()
+    }
+
+    fn on_song(&self, path: &str, song: &Song) {
+        for (ix, phrase) in song.phrases.iter().enumerate() {
+            for (row, notes) in self.find(phrase) {
+                let notes : Vec<String> = notes.into_iter().map(|n| Note(n).to_string()).collect();
+                println!("{}:PHRASE {:02X}:{:X} {}", path, ix, row, notes.join(" "));
+            }
+        }
+    }
+
+    fn on_file(&self, cwd: &Path, file: &M8File) {
+        if let M8Content::Song(song) = &file.content {
+            self.on_song(&file.relative_path(cwd).display().to_string(), song);
+        }
+    }
+}
+
+/// Find the phrases playing the melody in the songs of a given path.
+pub fn grep_melody(cwd: &Path, melody: &Melody, path : &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    Library::new(cwd)
+        .songs_only()
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            melody.on_file(cwd, &file);
+            Ok(())
+        })
+}
@@ -0,0 +1,203 @@
+use std::{fs, path::{Component, Path}};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::types::M8FstoErr;
+
+/// File at the backup root listing the files and folders to
+/// never scan or rewrite, with gitignore-style patterns.
+pub const IGNORE_FILE : &str = ".m8fstoignore";
+
+/// `*` and `?` stop at folder separators, like in gitignore
+const MATCH_OPTIONS : MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false
+};
+
+/// One line of an ignore file
+struct IgnoreRule {
+    pattern: Pattern,
+
+    /// Line starting with '!', including back a path
+    negated: bool,
+
+    /// Line ending with '/', only matching folders
+    dir_only: bool
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Result<Option<IgnoreRule>, M8FstoErr> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None)
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line))
+        };
+
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line)
+        };
+
+        // a pattern with a separator is relative to the root,
+        // otherwise it matches at any depth.
+        let pattern = match line.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if line.contains('/') => line.to_string(),
+            None => format!("**/{}", line)
+        };
+
+        let pattern = Pattern::new(&pattern)
+            .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{} : {:?}", line, e) })?;
+
+        Ok(Some(IgnoreRule { pattern, negated, dir_only }))
+    }
+}
+
+/// Files and folders skipped when walking a backup, from the ignore
+/// file and the `--exclude` flags.
+#[derive(Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>
+}
+
+impl IgnoreRules {
+    /// Rules of the ignore file at the root of the backup, if any
+    pub fn load(root: &Path) -> Result<IgnoreRules, M8FstoErr> {
+        let path = root.join(IGNORE_FILE);
+        let mut rules = IgnoreRules::default();
+        if !path.is_file() {
+            return Ok(rules)
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| M8FstoErr::CannotReadFile { path: path.clone(), reason: format!("{:?}", e) })?;
+
+        for line in content.lines() {
+            rules.add(line)?;
+        }
+
+        Ok(rules)
+    }
+
+    /// Add a pattern, with the ignore file syntax
+    pub fn add(&mut self, pattern: &str) -> Result<(), M8FstoErr> {
+        if let Some(rule) = IgnoreRule::parse(pattern)? {
+            self.rules.push(rule);
+        }
+        Ok(())
+    }
+
+    /// The last matching rule decides
+    fn matches(&self, rel: &str, is_dir: bool) -> bool {
+        self.rules.iter()
+            .rev()
+            .find(|r| (is_dir || !r.dir_only) && r.pattern.matches_with(rel, MATCH_OPTIONS))
+            .is_some_and(|r| !r.negated)
+    }
+
+    /// Is the path, relative to the root, ignored. Like with git, a path
+    /// in an ignored folder cannot be included back.
+    pub fn is_ignored(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false
+        }
+
+        let names : Vec<String> = rel.components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None
+            })
+            .collect();
+
+        (1 ..= names.len()).any(|depth| {
+            let prefix = names[.. depth].join("/");
+            self.matches(&prefix, depth < names.len() || is_dir)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::IgnoreRules;
+
+    fn rules(lines: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::default();
+        for line in lines {
+            rules.add(line).unwrap();
+        }
+        rules
+    }
+
+    fn ignored(rules: &IgnoreRules, path: &str, is_dir: bool) -> bool {
+        rules.is_ignored(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn no_rules_ignore_nothing() {
+        let r = rules(&["", "# Songs/"]);
+        assert!(!ignored(&r, "Songs", true));
+        assert!(!ignored(&r, "# Songs", true));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_at_any_depth() {
+        let r = rules(&["*.tmp"]);
+        assert!(ignored(&r, "a.tmp", false));
+        assert!(ignored(&r, "Songs/live/a.tmp", false));
+        assert!(!ignored(&r, "Songs/a.tmp.m8s", false));
+    }
+
+    #[test]
+    fn anchored_patterns_match_from_the_root() {
+        let r = rules(&["/Trash", "Songs/old"]);
+        assert!(ignored(&r, "Trash", true));
+        assert!(ignored(&r, "Trash/SONG.m8s", false));
+        assert!(!ignored(&r, "Songs/Trash", true));
+        assert!(ignored(&r, "Songs/old/SONG.m8s", false));
+        assert!(!ignored(&r, "Live/Songs/old/SONG.m8s", false));
+    }
+
+    #[test]
+    fn star_stops_at_separators() {
+        let r = rules(&["Songs/*.m8s"]);
+        assert!(ignored(&r, "Songs/A.m8s", false));
+        assert!(!ignored(&r, "Songs/live/A.m8s", false));
+    }
+
+    #[test]
+    fn dir_only_patterns_skip_files() {
+        let r = rules(&["backup/"]);
+        assert!(ignored(&r, "backup", true));
+        assert!(ignored(&r, "Songs/backup/A.m8s", false));
+        assert!(!ignored(&r, "Songs/backup", false));
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let r = rules(&["*.m8s", "!KEEP.m8s"]);
+        assert!(ignored(&r, "Songs/A.m8s", false));
+        assert!(!ignored(&r, "Songs/KEEP.m8s", false));
+
+        let r = rules(&["!KEEP.m8s", "*.m8s"]);
+        assert!(ignored(&r, "Songs/KEEP.m8s", false));
+    }
+
+    #[test]
+    fn ignored_folders_cannot_be_included_back() {
+        let r = rules(&["/Trash/", "!/Trash/KEEP.m8s"]);
+        assert!(ignored(&r, "Trash/KEEP.m8s", false));
+    }
+
+    #[test]
+    fn escaped_leading_bang() {
+        let r = rules(&["\\!draft.m8s"]);
+        assert!(ignored(&r, "Songs/!draft.m8s", false));
+        assert!(!ignored(&r, "Songs/draft.m8s", false));
+    }
+}
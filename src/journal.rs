@@ -0,0 +1,492 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+
+use crate::types::{combine, FlagBag, M8FstoErr};
+
+/// Folder, in the backup root, keeping the journal of the last operation
+pub const JOURNAL_FOLDER : &str = ".m8fsto-journal";
+
+/// Folder used while the journal is being written
+const JOURNAL_TEMP_FOLDER : &str = ".m8fsto-journal.tmp";
+
+/// File listing the changes done by the operation
+const JOURNAL_INDEX : &str = "journal.txt";
+
+/// First line of the journal index
+const JOURNAL_HEADER : &str = "m8fsto journal 1";
+
+/// Suffix of the temporary files written before replacing the originals
+const TEMP_SUFFIX : &str = ".m8fsto-tmp";
+
+/// Folder, in the backup root, where removed files are kept, mirroring
+/// the backup hierarchy. Being hidden, it is never searched.
+pub const TRASH_FOLDER : &str = ".m8fsto-trash";
+
+/// FNV-1a hash, used to check a file has not been changed since
+/// the operation.
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn journal_error(reason: String) -> M8FstoErr {
+    M8FstoErr::JournalError { reason }
+}
+
+/// Replace a file, writing a temporary file first so the original
+/// is never left half written.
+fn replace_file(path: &Path, data: &[u8]) -> Result<(), M8FstoErr> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(TEMP_SUFFIX);
+    let temp = PathBuf::from(temp_name);
+
+    fs::write(&temp, data)
+        .map_err(|e| M8FstoErr::SongSerializationError {
+            destination: format!("{:?}", temp),
+            reason: format!("{:?}", e)
+        })?;
+
+    fs::rename(&temp, path)
+        .map_err(|_| M8FstoErr::RenameFailure { path: format!("{:?}", temp) })
+}
+
+/// Create the folder receiving a renamed file, like the trash
+fn create_parent(path: &Path) -> Result<(), M8FstoErr> {
+    match path.parent() {
+        Some(parent) if !parent.exists() => fs::create_dir_all(parent)
+            .map_err(|e| M8FstoErr::FolderCreationError { path: parent.to_path_buf(), reason: format!("{:?}", e) }),
+        _ => Ok(())
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), M8FstoErr> {
+    fs::rename(from, to)
+        .map_err(|_| M8FstoErr::RenameFailure { path: format!("{:?}", from) })
+}
+
+/// Set of file rewrites and renames applied together. The original
+/// content is saved in a journal before anything is touched, allowing
+/// to roll back a failed operation, or to undo the last one.
+pub struct Transaction {
+    root: PathBuf,
+
+    /// Command line of the operation, printed when undoing
+    description: String,
+
+    /// Files to rewrite with their new content
+    writes: Vec<(PathBuf, Vec<u8>)>,
+
+    /// Files or folders to rename, after the rewrites
+    renames: Vec<(PathBuf, PathBuf)>
+}
+
+/// Change recorded in the journal
+enum Entry {
+    /// Rewritten file, its original content is in `<index>.orig`
+    File { index: usize, hash: u64, path: PathBuf },
+
+    /// Renamed file or folder
+    Rename { from: PathBuf, to: PathBuf }
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        match self {
+            Entry::File { index, hash, path } =>
+                format!("file\t{}\t{:016x}\t{}", index, hash, path.display()),
+            Entry::Rename { from, to } =>
+                format!("rename\t{}\t{}", from.display(), to.display())
+        }
+    }
+
+    fn of_line(line: &str) -> Option<Entry> {
+        let fields : Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["file", index, hash, path] => Some(Entry::File {
+                index: index.parse().ok()?,
+                hash: u64::from_str_radix(hash, 16).ok()?,
+                path: PathBuf::from(path)
+            }),
+            ["rename", from, to] => Some(Entry::Rename {
+                from: PathBuf::from(from),
+                to: PathBuf::from(to)
+            }),
+            _ => None
+        }
+    }
+}
+
+impl Transaction {
+    pub fn new(root: &Path, description: String) -> Transaction {
+        Transaction {
+            root: root.to_path_buf(),
+            description,
+            writes: vec![],
+            renames: vec![]
+        }
+    }
+
+    pub fn write(&mut self, path: PathBuf, data: Vec<u8>) {
+        self.writes.push((path, data));
+    }
+
+    pub fn rename(&mut self, from: PathBuf, to: PathBuf) {
+        self.renames.push((from, to));
+    }
+
+    /// Remove a file or folder, it is moved in the trash folder to be
+    /// restored by `undo`, or by hand after later operations. A file
+    /// removed again gets a `~N` suffix.
+    pub fn remove(&mut self, path: PathBuf) {
+        let rel = path.strip_prefix(&self.root).unwrap_or(&path);
+        let in_trash = self.root.join(TRASH_FOLDER).join(rel);
+
+        let mut kept = in_trash.clone();
+        let mut count = 1;
+        while kept.exists() || self.renames.iter().any(|(_, to)| *to == kept) {
+            let stem = in_trash.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match in_trash.extension() {
+                Some(ext) => format!("{}~{}.{}", stem, count, ext.to_string_lossy()),
+                None => format!("{}~{}", stem, count)
+            };
+            kept = in_trash.with_file_name(name);
+            count += 1;
+        }
+
+        self.renames.push((path, kept));
+    }
+
+    /// Save the original content of the files to be rewritten in the
+    /// temporary journal folder, returned.
+    fn write_journal(&self) -> Result<PathBuf, M8FstoErr> {
+        let temp = self.root.join(JOURNAL_TEMP_FOLDER);
+        if temp.exists() {
+            fs::remove_dir_all(&temp)
+                .map_err(|e| M8FstoErr::FileRemovalFailure { path: temp.clone(), reason: format!("{:?}", e) })?;
+        }
+
+        fs::create_dir_all(&temp)
+            .map_err(|e| M8FstoErr::FolderCreationError { path: temp.clone(), reason: format!("{:?}", e) })?;
+
+        let mut lines = vec![
+            JOURNAL_HEADER.to_string(),
+            format!("command\t{}", self.description)
+        ];
+
+        for (index, (path, data)) in self.writes.iter().enumerate() {
+            let original_path = temp.join(format!("{}.orig", index));
+            fs::copy(path, &original_path)
+                .map_err(|e| M8FstoErr::SampleCopyError {
+                    path: path.clone(),
+                    to: original_path.clone(),
+                    reason: format!("{:?}", e)
+                })?;
+
+            lines.push(Entry::File { index, hash: content_hash(data), path: path.clone() }.to_line());
+        }
+
+        for (from, to) in self.renames.iter() {
+            lines.push(Entry::Rename { from: from.clone(), to: to.clone() }.to_line());
+        }
+
+        let index_path = temp.join(JOURNAL_INDEX);
+        fs::write(&index_path, lines.join("\n") + "\n")
+            .map_err(|e| journal_error(format!("cannot write {:?} : {:?}", index_path, e)))?;
+
+        Ok(temp)
+    }
+
+    /// Replace the journal of the previous operation by the new one
+    fn install_journal(&self, temp: &Path) -> Result<(), M8FstoErr> {
+        let journal = self.root.join(JOURNAL_FOLDER);
+        if journal.exists() {
+            fs::remove_dir_all(&journal)
+                .map_err(|e| M8FstoErr::FileRemovalFailure { path: journal.clone(), reason: format!("{:?}", e) })?;
+        }
+
+        rename(temp, &journal)
+    }
+
+    /// Apply all the changes, rolling back the applied ones if one fails.
+    /// The new journal replaces the previous one only once the changes are
+    /// applied, to be able to undo the operation. After a rollback, the
+    /// previous operation can still be undone.
+    pub fn commit(self) -> Result<(), M8FstoErr> {
+        let temp = self.write_journal()?;
+        let mut written = 0;
+        let mut renamed = 0;
+
+        let applied : Result<(), M8FstoErr> = self.writes.iter()
+            .try_for_each(|(path, data)| {
+                replace_file(path, data)?;
+                written += 1;
+                Ok(())
+            })
+            .and_then(|()| self.renames.iter().try_for_each(|(from, to)| {
+                create_parent(to)?;
+                rename(from, to)?;
+                renamed += 1;
+                Ok(())
+            }));
+
+        let Err(failure) = applied else {
+            return self.install_journal(&temp)
+                .map_err(|e| e.combine(journal_error(String::from("operation done, but it cannot be undone"))))
+        };
+
+        let mut rollback_errors = None;
+        for (from, to) in self.renames.iter().take(renamed).rev() {
+            if let Err(e) = rename(to, from) {
+                rollback_errors = combine(rollback_errors, e);
+            }
+        }
+
+        for (index, (path, _)) in self.writes.iter().enumerate().take(written) {
+            let restored = fs::read(temp.join(format!("{}.orig", index)))
+                .map_err(|e| M8FstoErr::CannotReadFile { path: path.clone(), reason: format!("{:?}", e) })
+                .and_then(|original| replace_file(path, &original));
+
+            if let Err(e) = restored {
+                rollback_errors = combine(rollback_errors, e);
+            }
+        }
+
+        match rollback_errors {
+            None => {
+                let _ = fs::remove_dir_all(&temp);
+                Err(failure.combine(journal_error(String::from("operation failed, all changes were rolled back"))))
+            }
+            Some(errs) => {
+                let failure = failure.combine(errs);
+                match self.install_journal(&temp) {
+                    Ok(()) => Err(failure.combine(journal_error(String::from("rollback failed, try `m8fsto undo`")))),
+                    Err(e) => Err(failure.combine(e).combine(journal_error(format!("rollback failed, original files are in {:?}", temp))))
+                }
+            }
+        }
+    }
+}
+
+/// Read the journal of the last operation
+fn read_journal(journal: &Path) -> Result<(String, Vec<Entry>), M8FstoErr> {
+    let index_path = journal.join(JOURNAL_INDEX);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|_| journal_error(String::from("no operation to undo")))?;
+
+    let mut lines = content.lines();
+    if lines.next() != Some(JOURNAL_HEADER) {
+        return Err(journal_error(format!("{:?} is not a journal", index_path)))
+    }
+
+    let description = lines.next()
+        .and_then(|l| l.strip_prefix("command\t"))
+        .unwrap_or("")
+        .to_string();
+
+    let entries = lines
+        .filter(|l| !l.is_empty())
+        .map(|l| Entry::of_line(l).ok_or_else(|| journal_error(format!("invalid journal line '{}'", l))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((description, entries))
+}
+
+/// Where a rewritten file is after the renames of the operation, it
+/// may be inside a moved folder.
+fn current_path(path: &Path, entries: &[Entry]) -> PathBuf {
+    entries.iter().fold(path.to_path_buf(), |current, entry| match entry {
+        Entry::Rename { from, to } => match current.strip_prefix(from) {
+            Ok(rest) if rest.as_os_str().is_empty() => to.clone(),
+            Ok(rest) => to.join(rest),
+            Err(_) => current
+        },
+        Entry::File { .. } => current
+    })
+}
+
+/// Check that the changes of the journal can be undone. Renames are
+/// undone in reverse order, a path freed by a later rename can be used
+/// by an earlier one.
+fn check_undo(entries: &[Entry]) -> Option<M8FstoErr> {
+    let mut errors = None;
+    let mut freed = HashSet::new();
+    let mut taken = HashSet::new();
+
+    for entry in entries.iter().rev() {
+        match entry {
+            Entry::File { hash, path, .. } => {
+                let unchanged = fs::read(current_path(path, entries))
+                    .is_ok_and(|data| content_hash(&data) == *hash);
+                if !unchanged {
+                    errors = combine(errors, journal_error(format!("{:?} changed since the operation", path)));
+                }
+            }
+            Entry::Rename { from, to } => {
+                let to_exists = taken.contains(to) || (to.exists() && !freed.contains(to));
+                let from_exists = taken.contains(from) || (from.exists() && !freed.contains(from));
+                if !to_exists || from_exists {
+                    errors = combine(errors, journal_error(format!("cannot move back {:?} to {:?}", to, from)));
+                }
+
+                taken.remove(to);
+                freed.insert(to.clone());
+                freed.remove(from);
+                taken.insert(from.clone());
+            }
+        }
+    }
+
+    errors
+}
+
+/// Undo the last journaled operation, restoring the rewritten files
+/// and moving back the renamed ones.
+pub fn undo(root: &Path, flags: FlagBag) -> Result<(), M8FstoErr> {
+    let journal = root.join(JOURNAL_FOLDER);
+    let (description, entries) = read_journal(&journal)?;
+
+    println!("Undoing \"{}\"", description);
+
+    if let Some(errs) = check_undo(&entries) {
+        if !flags.force {
+            return Err(errs)
+        }
+        eprintln!("{}", errs);
+    }
+
+    let mut errors = None;
+    for entry in entries.iter().rev() {
+        match entry {
+            Entry::Rename { from, to } => {
+                println!(" * move back {:?} -> {:?}", to, from);
+                if flags.dry_run || !to.exists() || from.exists() { continue; }
+
+                if let Err(e) = rename(to, from) {
+                    errors = combine(errors, e);
+                }
+            }
+            Entry::File { index, path, .. } => {
+                println!(" * restore {:?}", path);
+                if flags.dry_run { continue; }
+
+                let restored = fs::read(journal.join(format!("{}.orig", index)))
+                    .map_err(|e| M8FstoErr::CannotReadFile { path: path.clone(), reason: format!("{:?}", e) })
+                    .and_then(|original| replace_file(path, &original));
+
+                if let Err(e) = restored {
+                    errors = combine(errors, e);
+                }
+            }
+        }
+    }
+
+    if flags.dry_run {
+        return Ok(())
+    }
+
+    match errors {
+        None => fs::remove_dir_all(&journal)
+            .map_err(|e| M8FstoErr::FileRemovalFailure { path: journal, reason: format!("{:?}", e) }),
+        Some(e) => Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::types::FlagBag;
+    use super::{undo, Transaction, JOURNAL_FOLDER};
+
+    /// Empty folder for a test
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("m8fsto-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    const FLAGS : FlagBag = FlagBag { dry_run: false, force: false, verbose: false };
+
+    #[test]
+    fn failed_operation_keeps_the_previous_journal() {
+        let root = test_root("failed");
+        let (a, b) = (root.join("A.m8s"), root.join("B.m8s"));
+        fs::write(&a, "a0").unwrap();
+        fs::write(&b, "b0").unwrap();
+
+        let mut first = Transaction::new(&root, String::from("first"));
+        first.write(a.clone(), b"a1".to_vec());
+        first.commit().unwrap();
+
+        let mut failing = Transaction::new(&root, String::from("failing"));
+        failing.write(b.clone(), b"b1".to_vec());
+        failing.rename(root.join("missing.wav"), root.join("moved.wav"));
+        assert!(failing.commit().is_err());
+        assert_eq!(fs::read(&b).unwrap(), b"b0");
+
+        undo(&root, FLAGS).unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b"a0");
+        assert!(!root.join(JOURNAL_FOLDER).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn undo_files_rewritten_in_a_moved_folder() {
+        let root = test_root("moved");
+        fs::create_dir_all(root.join("Kit")).unwrap();
+        let instrument = root.join("Kit/snare.m8i");
+        fs::write(&instrument, "old").unwrap();
+
+        let mut moving = Transaction::new(&root, String::from("mv"));
+        moving.write(instrument.clone(), b"new".to_vec());
+        moving.rename(root.join("Kit"), root.join("Drums"));
+        moving.commit().unwrap();
+        assert_eq!(fs::read(root.join("Drums/snare.m8i")).unwrap(), b"new");
+
+        undo(&root, FLAGS).unwrap();
+        assert_eq!(fs::read(&instrument).unwrap(), b"old");
+        assert!(!root.join("Drums").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn undo_chained_renames() {
+        let root = test_root("chained");
+        let (sample, kept, converted) = (root.join("pad.wav"), root.join("pad.orig"), root.join("pad.tmp"));
+        fs::write(&sample, "original").unwrap();
+        fs::write(&converted, "converted").unwrap();
+
+        let mut replacing = Transaction::new(&root, String::from("convert"));
+        replacing.rename(sample.clone(), kept.clone());
+        replacing.rename(converted.clone(), sample.clone());
+        replacing.commit().unwrap();
+        assert_eq!(fs::read(&sample).unwrap(), b"converted");
+
+        undo(&root, FLAGS).unwrap();
+        assert_eq!(fs::read(&sample).unwrap(), b"original");
+        assert_eq!(fs::read(&converted).unwrap(), b"converted");
+        assert!(!kept.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn removed_files_outlive_the_next_operation() {
+        let root = test_root("removed");
+        let (sample, song) = (root.join("kick.wav"), root.join("A.m8s"));
+        fs::write(&sample, "kick").unwrap();
+        fs::write(&song, "a0").unwrap();
+
+        let mut removing = Transaction::new(&root, String::from("rm"));
+        removing.remove(sample.clone());
+        removing.commit().unwrap();
+
+        let mut next = Transaction::new(&root, String::from("next"));
+        next.write(song, b"a1".to_vec());
+        next.commit().unwrap();
+
+        assert!(!sample.exists());
+        assert_eq!(fs::read(root.join(super::TRASH_FOLDER).join("kick.wav")).unwrap(), b"kick");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
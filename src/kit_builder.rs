@@ -0,0 +1,111 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+
+use m8_file_parser::{Instrument, Song};
+
+use crate::{
+    metadata::is_sample_file,
+    sampler_gen::{device_sample_path, load_base_song, new_sampler, unique_name, write_instrument, write_song},
+    types::{combine, M8FstoErr}
+};
+
+/// List the samples directly in a folder, sorted by name.
+fn list_samples(dir: &Path) -> Result<Vec<PathBuf>, M8FstoErr> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| M8FstoErr::CannotReadFile { path: dir.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    let mut samples : Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_sample_file(path))
+        .collect();
+
+    samples.sort();
+    Ok(samples)
+}
+
+/// Create one sampler instrument per sample of the folder, optionally
+/// writing a song based on `base_song` with the instruments loaded
+/// from `first_slot`.
+pub fn build_kit(
+    backup_root: &Path,
+    dir: &Path,
+    out: &Path,
+    base_song: &Option<String>,
+    first_slot: usize) -> Result<(), M8FstoErr> {
+
+    let samples = list_samples(dir)?;
+    if samples.is_empty() {
+        println!("No samples in {:?}", dir);
+        return Ok(())
+    }
+
+    // check the song first, avoiding writing instruments for nothing
+    let base = match base_song {
+        None => None,
+        Some(song_path) => {
+            let song_path = PathBuf::from(song_path);
+            let (data, song) = load_base_song(&song_path)?;
+            if first_slot + samples.len() > Song::N_INSTRUMENTS {
+                return Err(M8FstoErr::InvalidPath {
+                    reason: format!("{} samples do not fit in the song from slot {:02X}", samples.len(), first_slot)
+                })
+            }
+            Some((song_path, data, song))
+        }
+    };
+
+    fs::create_dir_all(out)
+        .map_err(|e| M8FstoErr::FolderCreationError { path: out.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    let mut errors = None;
+    let mut used_names = HashSet::new();
+    let mut instruments = vec![];
+
+    for sample in samples.iter() {
+        let sample_path = match device_sample_path(backup_root, sample) {
+            Ok(p) => p,
+            Err(e) => {
+                errors = combine(errors, e);
+                continue;
+            }
+        };
+
+        let stem = sample.file_stem().unwrap_or_default().to_string_lossy();
+        let name = unique_name(&mut used_names, &stem);
+        let instrument = Instrument::Sampler(new_sampler(&name, &sample_path));
+
+        let instrument_path = out.join(format!("{}.m8i", name));
+        println!(" * {} : {}", name, sample_path);
+
+        match write_instrument(&instrument_path, instrument.clone()) {
+            Ok(()) => instruments.push(instrument),
+            Err(e) => errors = combine(errors, e)
+        }
+    }
+
+    if let Some((song_path, data, mut song)) = base {
+        for (i, instrument) in instruments.into_iter().enumerate() {
+            let slot = first_slot + i;
+            song.instruments[slot] = match instrument {
+                Instrument::Sampler(mut sampler) => {
+                    sampler.number = slot as u8;
+                    Instrument::Sampler(sampler)
+                }
+                other => other
+            };
+        }
+
+        let kit_name = out.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let out_song = out.join(format!("{}.m8s", kit_name));
+
+        match write_song(&out_song, data, &song) {
+            Ok(()) => println!("Wrote song {:?} based on {:?}", out_song, song_path),
+            Err(e) => errors = combine(errors, e)
+        }
+    }
+
+    match errors {
+        None => Ok(()),
+        Some(e) => Err(e)
+    }
+}
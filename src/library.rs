@@ -0,0 +1,544 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::IsTerminal,
+    path::{Component, Path, PathBuf},
+    thread
+};
+
+use glob::{glob, Pattern};
+use m8_file_parser::{reader::*, writer::Writer, Instrument, InstrumentWithEq, Song};
+
+use crate::{ignore::IgnoreRules, move_samples::normalize_path, query::Query, types::{combine, M8FstoErr}};
+
+/// Files searched in folders, songs and instruments
+const M8_FILES_PATTERN : &str = "**/*.m8[si]";
+
+/// Files searched in folders when only songs are wanted
+const SONGS_PATTERN : &str = "**/*.m8s";
+
+/// Files parsed together by the worker threads before being handed,
+/// in order, to the command.
+const FILES_PER_THREAD : usize = 16;
+
+/// Parsed content of a song or instrument file
+pub enum M8Content {
+    Song(Box<Song>),
+    Instrument(Box<InstrumentWithEq>)
+}
+
+/// Song or instrument file found in the library
+pub struct M8File {
+    pub path: PathBuf,
+
+    /// Raw content of the file, used to write it back
+    pub data: Vec<u8>,
+
+    pub content: M8Content
+}
+
+impl M8File {
+    /// Parse a song, falling back to an instrument if allowed
+    pub fn parse(path: PathBuf, data: Vec<u8>, instruments: bool) -> Result<M8File, M8FstoErr> {
+        let mut reader = Reader::new(data.clone());
+
+        let content = match Song::read_from_reader(&mut reader) {
+            Ok(song) => M8Content::Song(Box::new(song)),
+            Err(e) if !instruments => {
+                return Err(M8FstoErr::UnparseableM8File { path, reason: format!("{:?}", e) })
+            }
+            Err(e) => {
+                reader.set_pos(0);
+                match Instrument::read_from_reader(&mut reader) {
+                    Ok(instr_eq) => M8Content::Instrument(Box::new(instr_eq)),
+                    Err(ei) => return Err(M8FstoErr::UnparseableM8File {
+                        path,
+                        reason: format!("{:?}\n{:?}", e, ei)
+                    })
+                }
+            }
+        };
+
+        Ok(M8File { path, data, content })
+    }
+
+    /// Instruments of the file with their number, an instrument
+    /// file has a single instrument numbered 0.
+    pub fn instruments(&self) -> Vec<(usize, &Instrument)> {
+        match &self.content {
+            M8Content::Song(song) => song.instruments.iter().enumerate().collect(),
+            M8Content::Instrument(instr_eq) => vec![(0, &instr_eq.instrument)]
+        }
+    }
+
+    pub fn instruments_mut(&mut self) -> Vec<(usize, &mut Instrument)> {
+        match &mut self.content {
+            M8Content::Song(song) => song.instruments.iter_mut().enumerate().collect(),
+            M8Content::Instrument(instr_eq) => vec![(0, &mut instr_eq.instrument)]
+        }
+    }
+
+    /// Path of the file from the root, for display
+    pub fn relative_path<'a>(&'a self, root: &Path) -> &'a Path {
+        self.path.strip_prefix(root).unwrap_or(&self.path)
+    }
+
+    /// Serialize the file with its changes
+    pub fn serialize(&self) -> Result<Vec<u8>, M8FstoErr> {
+        match &self.content {
+            M8Content::Song(song) => {
+                let mut writer = Writer::new(self.data.clone());
+                song.write(&mut writer)
+                    .map_err(|reason| M8FstoErr::SongSerializationError {
+                        destination: format!("{:?}", self.path),
+                        reason
+                    })?;
+                Ok(writer.finish())
+            }
+            M8Content::Instrument(instr_eq) => {
+                let mut writer = Writer::new_instrument_writer(instr_eq.eq.is_some());
+                instr_eq.write(&mut writer);
+                Ok(writer.finish())
+            }
+        }
+    }
+}
+
+/// Absolute path, without `.` and `..`, relative paths are taken
+/// from the current directory.
+fn absolute_path(path: &Path) -> PathBuf {
+    normalize_path(&std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()))
+}
+
+fn read_file(path: &Path, instruments: bool) -> Result<M8File, M8FstoErr> {
+    let data = fs::read(path)
+        .map_err(|e| M8FstoErr::CannotReadFile { path: path.to_path_buf(), reason: format!("{:?}", e) })?;
+    M8File::parse(path.to_path_buf(), data, instruments)
+}
+
+/// Read and parse the files, spreading them over the threads. The
+/// results are in the order of the paths.
+fn read_files(paths: &[PathBuf], instruments: bool, threads: usize) -> Vec<Result<M8File, M8FstoErr>> {
+    if threads <= 1 || paths.len() <= 1 {
+        return paths.iter().map(|p| read_file(p, instruments)).collect()
+    }
+
+    let per_thread = paths.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let workers : Vec<_> = paths
+            .chunks(per_thread)
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter().map(|p| read_file(p, instruments)).collect::<Vec<_>>()
+            }))
+            .collect();
+
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    })
+}
+
+/// Progress of a walk, printed on the terminal
+struct Progress {
+    enabled: bool,
+    total: usize
+}
+
+impl Progress {
+    fn show(&self, index: usize, path: &Path) {
+        if self.enabled {
+            eprint!("\r\x1b[2K[{}/{}] {}", index + 1, self.total, path.display());
+        }
+    }
+
+    fn clear(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// Songs and instruments of a backup, to be walked by the commands
+pub struct Library {
+    root: PathBuf,
+
+    /// Root made absolute, to find the files under it
+    absolute_root: PathBuf,
+
+    /// Root with the links resolved, if it exists
+    canonical_root: Option<PathBuf>,
+
+    /// Files, folders or glob patterns to search, the whole
+    /// root when empty.
+    includes: Vec<String>,
+
+    /// Files and folders to skip, from the ignore file of the
+    /// root and the `--exclude` flags.
+    ignore: IgnoreRules,
+
+    /// Invalid ignore file or exclude pattern, nothing is walked
+    setup_error: Option<M8FstoErr>,
+
+    /// Only walk the songs matching the query, from `--where`
+    query: Option<Query>,
+
+    /// Parse the files which are not songs as instruments
+    instruments: bool,
+
+    /// Print the walk progress
+    progress: bool,
+
+    /// Threads reading and parsing the files
+    threads: usize
+}
+
+impl Library {
+    pub fn new(root: &Path) -> Library {
+        let (ignore, setup_error) = match IgnoreRules::load(root) {
+            Ok(ignore) => (ignore, None),
+            Err(e) => (IgnoreRules::default(), Some(e))
+        };
+
+        Library {
+            root: root.to_path_buf(),
+            absolute_root: absolute_path(root),
+            canonical_root: fs::canonicalize(root).ok(),
+            includes: vec![],
+            ignore,
+            setup_error,
+            query: None,
+            instruments: true,
+            progress: std::io::stderr().is_terminal(),
+            threads: thread::available_parallelism().map_or(1, |n| n.get())
+        }
+    }
+
+    /// Only search these files, folders or glob patterns
+    pub fn include(mut self, paths: &[String]) -> Library {
+        self.includes.extend(paths.iter().cloned());
+        self
+    }
+
+    /// Skip the files and folders matching the patterns, written
+    /// like the lines of the ignore file.
+    pub fn exclude(mut self, patterns: &[String]) -> Result<Library, M8FstoErr> {
+        for pattern in patterns {
+            self.ignore.add(pattern)?;
+        }
+        Ok(self)
+    }
+
+    /// Only walk the songs matching the query, instrument files
+    /// never match a query.
+    pub fn select(mut self, query: &Option<String>) -> Result<Library, M8FstoErr> {
+        if let Some(query) = query {
+            self.query = Some(Query::parse(query)?);
+        }
+        Ok(self)
+    }
+
+    /// Is the file selected by the query, if any
+    fn is_selected(&self, file: &M8File) -> bool {
+        match (&self.query, &file.content) {
+            (None, _) => true,
+            (Some(query), M8Content::Song(song)) => query.matches(file.relative_path(&self.root), song),
+            (Some(_), M8Content::Instrument(_)) => false
+        }
+    }
+
+    /// Only look for songs
+    pub fn songs_only(mut self) -> Library {
+        self.instruments = false;
+        self
+    }
+
+    /// Path of a file from the root, None if the file is not under the
+    /// root. Paths are compared made absolute, then with the links resolved.
+    fn relative_to_root(&self, path: &Path) -> Option<PathBuf> {
+        if let Ok(rel) = absolute_path(path).strip_prefix(&self.absolute_root) {
+            return Some(rel.to_path_buf())
+        }
+
+        let canonical_root = self.canonical_root.as_ref()?;
+        let canonical = fs::canonicalize(path).ok()?;
+        canonical.strip_prefix(canonical_root).ok().map(Path::to_path_buf)
+    }
+
+    /// Hidden files and folders (like the journal) and the ignored ones
+    /// are skipped. Only the part of the path under the root is checked,
+    /// files outside of the root are never excluded.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let Some(rel) = self.relative_to_root(path) else { return false };
+        let hidden = rel.components().any(|c| match c {
+            Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+            _ => false
+        });
+
+        hidden || self.ignore.is_ignored(&rel, path.is_dir())
+    }
+
+    fn search_pattern(&self, folder: &Path) -> String {
+        format!("{}/{}",
+            Pattern::escape(&folder.to_string_lossy()),
+            if self.instruments { M8_FILES_PATTERN } else { SONGS_PATTERN })
+    }
+
+    /// Files to walk, in search order and without duplicates
+    pub fn files(&self) -> (Vec<PathBuf>, Option<M8FstoErr>) {
+        if let Some(e) = &self.setup_error {
+            return (vec![], Some(e.clone()))
+        }
+
+        let mut errors = None;
+        let mut patterns = vec![];
+
+        if self.includes.is_empty() {
+            patterns.push(self.search_pattern(&self.root));
+        }
+
+        let mut found = vec![];
+        for include in self.includes.iter() {
+            let path = Path::new(include);
+            if path.is_file() {
+                found.push(path.to_path_buf());
+            } else if path.is_dir() {
+                patterns.push(self.search_pattern(path));
+            } else {
+                patterns.push(include.clone());
+            }
+        }
+
+        for pattern in patterns {
+            let entries = match glob(&pattern) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    errors = combine(errors, M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) });
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                match entry {
+                    Ok(path) if path.is_file() => found.push(path),
+                    Ok(_) => {}
+                    Err(e) => errors = combine(errors, M8FstoErr::CannotReadFile {
+                        path: e.path().to_path_buf(),
+                        reason: format!("{:?}", e.error())
+                    })
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        found.retain(|path| !self.is_excluded(path) && seen.insert(path.clone()));
+        (found, errors)
+    }
+
+    /// Read and parse every file, calling `on_file` in search order.
+    /// Files are parsed in parallel, but `on_file` is called from the
+    /// current thread, one file at a time. Errors are collected, the walk
+    /// never stops on an error. Files not selected by the query are skipped.
+    pub fn walk<F>(&self, mut on_file: F) -> Result<(), M8FstoErr>
+        where F: FnMut(M8File) -> Result<(), M8FstoErr> {
+
+        let (files, mut errors) = self.files();
+        let progress = Progress { enabled: self.progress, total: files.len() };
+        let batch = self.threads * FILES_PER_THREAD;
+
+        for (batch_index, paths) in files.chunks(batch).enumerate() {
+            if let Some(last) = paths.last() {
+                progress.show(batch_index * batch + paths.len() - 1, last);
+            }
+
+            for parsed in read_files(paths, self.instruments, self.threads) {
+                progress.clear();
+                let selected = parsed.map(|file| self.is_selected(&file).then_some(file));
+                if let Err(e) = selected.and_then(|file| file.map_or(Ok(()), &mut on_file)) {
+                    errors = combine(errors, e);
+                }
+            }
+        }
+
+        progress.clear();
+        match errors {
+            None => Ok(()),
+            Some(e) => Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::{Path, PathBuf}};
+    use m8_file_parser::Instrument;
+
+    use crate::{sampler_gen::{new_sampler, write_instrument}, types::M8FstoErr};
+    use super::{Library, M8Content};
+
+    const SONG : &[u8] = include_bytes!("../testdata/EMPTY.m8s");
+
+    /// Empty folder for a test
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("m8fsto-library-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn write(root: &Path, rel: &str, data: &[u8]) -> PathBuf {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Backup in a hidden folder, with an ignored folder
+    fn hidden_backup(name: &str) -> (PathBuf, PathBuf) {
+        let top = test_root(name);
+        let root = top.join(".backups/M8");
+        write(&root, "Songs/A.m8s", SONG);
+        write(&root, "Bundles/B.m8s", SONG);
+        write(&root, ".m8fsto-journal/C.m8s", SONG);
+        write(&root, ".m8fstoignore", b"Bundles/\n");
+        (top, root)
+    }
+
+    fn relative_names(library: &Library, root: &Path) -> Vec<String> {
+        let (files, errors) = library.files();
+        assert!(errors.is_none());
+        files.iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect()
+    }
+
+    #[test]
+    fn only_the_path_under_the_root_is_checked() {
+        let (top, root) = hidden_backup("under-root");
+        let library = Library::new(&root);
+
+        assert!(!library.is_excluded(&root.join("Songs/A.m8s")));
+        assert!(library.is_excluded(&root.join("Bundles/B.m8s")));
+        assert!(library.is_excluded(&root.join(".m8fsto-journal/C.m8s")));
+        assert!(!library.is_excluded(&top.join(".backups/other/Bundles/D.m8s")));
+        assert!(library.is_excluded(&root.join("Songs/../Bundles/B.m8s")));
+        assert_eq!(relative_names(&library, &root), vec!["Songs/A.m8s"]);
+        fs::remove_dir_all(&top).unwrap();
+    }
+
+    #[test]
+    fn absolute_includes_in_a_root_given_from_elsewhere() {
+        let (top, root) = hidden_backup("absolute-include");
+        let library = Library::new(&root)
+            .include(&[root.to_string_lossy().to_string(), root.join("Bundles").to_string_lossy().to_string()]);
+
+        assert_eq!(relative_names(&library, &root), vec!["Songs/A.m8s"]);
+        fs::remove_dir_all(&top).unwrap();
+    }
+
+    /// Root given relative to the current directory, but searched
+    /// with absolute paths.
+    #[cfg(unix)]
+    #[test]
+    fn relative_root_different_from_the_current_directory() {
+        let (top, root) = hidden_backup("relative-root");
+        let cwd = std::env::current_dir().unwrap();
+        let relative_root : PathBuf = cwd.components().skip(1).map(|_| "..").collect::<PathBuf>()
+            .join(root.strip_prefix("/").unwrap());
+
+        let library = Library::new(&relative_root)
+            .include(&[root.join("Songs").to_string_lossy().to_string(), root.join("Bundles").to_string_lossy().to_string()]);
+
+        assert!(library.is_excluded(&root.join("Bundles/B.m8s")));
+        assert!(!library.is_excluded(&relative_root.join("Songs/A.m8s")));
+        assert_eq!(relative_names(&library, &root), vec!["Songs/A.m8s"]);
+        fs::remove_dir_all(&top).unwrap();
+    }
+
+    /// Songs named after their index, an instrument file and a corrupt song
+    fn library_tree(name: &str, songs: usize) -> PathBuf {
+        let root = test_root(name);
+        for i in 0 .. songs {
+            write(&root, &format!("Songs/S{:03}.m8s", i), SONG);
+        }
+        write(&root, "Songs/S010_BROKEN.m8s", &SONG[.. 200]);
+        write_instrument(&root.join("Songs/KICK.m8i"), Instrument::Sampler(new_sampler("KICK", "/kick.wav"))).unwrap();
+        root
+    }
+
+    /// Walk the library, returning the walked file names and the errors
+    fn walk(library: &Library) -> (Vec<String>, Vec<M8FstoErr>) {
+        let mut names = vec![];
+        let walked = library.walk(|file| {
+            let kind = match file.content { M8Content::Song(_) => "song", M8Content::Instrument(_) => "instrument" };
+            names.push(format!("{} {}", file.path.file_name().unwrap().to_string_lossy(), kind));
+            Ok(())
+        });
+
+        let errors = match walked {
+            Ok(()) => vec![],
+            Err(M8FstoErr::MultiErrs { inner }) => inner,
+            Err(e) => vec![e]
+        };
+        (names, errors)
+    }
+
+    fn is_broken_song(error: &M8FstoErr) -> bool {
+        matches!(error, M8FstoErr::UnparseableM8File { path, .. } if path.ends_with("S010_BROKEN.m8s"))
+    }
+
+    #[test]
+    fn parallel_walk_keeps_the_search_order() {
+        let root = library_tree("order", 40);
+        let mut library = Library::new(&root).songs_only();
+        library.threads = 4;
+        library.progress = false;
+
+        let (names, errors) = walk(&library);
+        let mut expected : Vec<String> = (0 .. 40).map(|i| format!("S{:03}.m8s song", i)).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+
+        assert_eq!(errors.len(), 1);
+        assert!(is_broken_song(&errors[0]));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn errors_are_collected_without_stopping_the_walk() {
+        let root = library_tree("errors", 3);
+        let mut library = Library::new(&root);
+        library.progress = false;
+
+        let mut walked = 0;
+        let result = library.walk(|file| {
+            walked += 1;
+            Err(M8FstoErr::InvalidPath { reason: file.path.to_string_lossy().to_string() })
+        });
+
+        let Err(M8FstoErr::MultiErrs { inner }) = result else { panic!("errors expected") };
+        assert_eq!(walked, 4);
+        assert_eq!(inner.len(), 5);
+        assert_eq!(inner.iter().filter(|e| is_broken_song(e)).count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn instruments_are_only_walked_when_asked() {
+        let root = library_tree("instruments", 2);
+        let mut library = Library::new(&root);
+        library.progress = false;
+
+        let (names, errors) = walk(&library);
+        assert_eq!(names, vec!["KICK.m8i instrument", "S000.m8s song", "S001.m8s song"]);
+        assert_eq!(errors.len(), 1);
+
+        let mut library = Library::new(&root).songs_only();
+        library.progress = false;
+        assert_eq!(walk(&library).0, vec!["S000.m8s song", "S001.m8s song"]);
+
+        let mut library = Library::new(&root).select(&Some(String::from("name ~ '*'"))).unwrap();
+        library.progress = false;
+        assert_eq!(walk(&library).0, vec!["S000.m8s song", "S001.m8s song"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
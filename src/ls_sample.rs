@@ -1,90 +1,76 @@
 use std::{fs, path::Path};
-use glob::glob;
-use m8_file_parser::{reader::*, Instrument};
+use m8_file_parser::Instrument;
 
-use crate::types::M8FstoErr;
+use crate::{
+    broken_search::sample_to_absolute_path,
+    bundle::human_size,
+    library::{Library, M8File},
+    types::M8FstoErr,
+    wav::WavFile
+};
 
-fn on_file_blob(cwd: &Path, path: &Path, data: Vec<u8>) -> Result<(), M8FstoErr> {
-    let mut reader = Reader::new(data);
-    let song = m8_file_parser::Song::read_from_reader(&mut reader)
-        .map_err(|e| M8FstoErr::UnparseableM8File {
-            path: path.to_path_buf(),
-            reason: format!("{:?}", e)
-        })?;
+/// Print the header information of a sample file, along with
+/// the problems the M8 would have with it.
+fn print_details(backup_root: &Path, song_path: &Path, sample_path: &str) {
+    if sample_path.is_empty() {
+        return;
+    }
 
-    let mut has_seen_sample = false;
-    for (i, instr) in song.instruments.iter().enumerate() {
-        match instr {
-            Instrument::Sampler(sampler) => {
-                if !has_seen_sample {
-                    let rel_path =
-                        path.strip_prefix(cwd).unwrap_or(path);
+    let full_sample_path =
+        sample_to_absolute_path(backup_root, song_path, sample_path);
+
+    let Ok(meta) = fs::metadata(&full_sample_path) else {
+        println!("       ! missing file {:?}", full_sample_path);
+        return;
+    };
+
+    match WavFile::read(&full_sample_path) {
+        Err(_) => println!("       ! UNSUPPORTED not a WAV file, {}", human_size(meta.len())),
+        Ok(wav) => {
+            println!("       {}, {} ch, {} Hz, {:.2} s, {} cues, {}",
+                wav.format_name(),
+                wav.channels,
+                wav.sample_rate,
+                wav.duration(),
+                wav.cues.len(),
+                human_size(meta.len()));
 
-                    println!("\n{}", rel_path.display().to_string());
-                    has_seen_sample = true;
-                }
-                if sampler.name.len() > 0 {
-                    println!("  {:02X} {} : {}", i, sampler.name, sampler.sample_path);
-                } else {
-                    println!("  {:02X} : {}", i, sampler.sample_path);
-                }
+            for issue in wav.m8_issues() {
+                println!("       ! {}", issue);
             }
-            _ => {}
         }
     }
-
-    Ok(())
 }
 
-fn on_dir(cwd: &Path, path: &str) -> Result<(), M8FstoErr> {
-    let mut errors = vec![];
+fn on_file(cwd: &Path, details: Option<&Path>, file: &M8File) {
+    let mut has_seen_sample = false;
+    for (i, instr) in file.instruments() {
+        if let Instrument::Sampler(sampler) = instr {
+            if !has_seen_sample {
+                println!("\n{}", file.relative_path(cwd).display());
+                has_seen_sample = true;
+            }
+            if !sampler.name.is_empty() {
+                println!("  {:02X} {} : {}", i, sampler.name, sampler.sample_path);
+            } else {
+                println!("  {:02X} : {}", i, sampler.sample_path);
+            }
 
-    for entry in glob(path)
-        .map_err(|e|M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) })? {
-        match entry {
-            Err(e) => println!("{:?}", e),
-            Ok(path) => {
-                let try_as_file = fs::read(&path);
-                match try_as_file {
-                    Err(e) => {
-                        errors.push(M8FstoErr::CannotReadFile {
-                            path: path.to_path_buf(),
-                            reason: format!("{:?}", e)
-                        })
-                    }
-                    Ok(file_blob) => {
-                        match on_file_blob(cwd, path.as_path(), file_blob) {
-                            Ok(()) => {},
-                            Err(e) => errors.push(e)
-                        }
-                    }
-                }
+            if let Some(backup_root) = details {
+                print_details(backup_root, &file.path, &sampler.sample_path);
             }
         }
     }
-
-    if errors.len() == 0 {
-        Ok(())
-    } else if errors.len() == 1 {
-        Err(errors[0].clone())
-    } else {
-        Err(M8FstoErr::MultiErrs { inner: errors })
-    }
 }
 
-/// Try to list sample of a given path
-pub fn ls_sample(cwd: &Path, path : &Option<String>) -> Result<(), M8FstoErr> {
-    match path {
-        None => on_dir(cwd, "./"),
-        Some(path) => {
-            let try_as_file = fs::read(path);
-            match try_as_file {
-                Err(_) => { on_dir(cwd, path) }
-                Ok(file_blob) => {
-                    let as_path = Path::new(path);
-                    on_file_blob(cwd, as_path, file_blob)
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+/// Try to list sample of a given path, songs and instrument files, if
+/// `details` is set with the backup root, sample files are inspected.
+pub fn ls_sample(cwd: &Path, details: Option<&Path>, path : &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    Library::new(cwd)
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            on_file(cwd, details, &file);
+            Ok(())
+        })
+}
@@ -0,0 +1,175 @@
+use std::{cmp::Ordering, path::Path};
+use glob::{MatchOptions, Pattern};
+use m8_file_parser::Version;
+
+use crate::{
+    library::{Library, M8Content},
+    metadata::{compare_versions, parse_version, Comparison, SongStats},
+    types::M8FstoErr
+};
+
+/// Columns the catalog can be sorted by
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum SortKey {
+    Path,
+    Name,
+    Version,
+    Tempo,
+    Chains,
+    Phrases,
+    Instruments,
+    Samples
+}
+
+/// Song names are matched ignoring the case
+const MATCH_OPTIONS : MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false
+};
+
+/// Songs listed by the catalog
+pub struct SongFilter {
+    /// Comparison with a version, like `<6.0`
+    pub version: Option<String>,
+
+    /// Glob pattern on the song name
+    pub name: Option<String>,
+
+    /// Query on the song metadata, like `tempo > 140`
+    pub query: Option<String>
+}
+
+/// Line of the catalog
+struct SongEntry {
+    path: String,
+    name: String,
+    version: Version,
+    tempo: f32,
+    stats: SongStats
+}
+
+impl SongEntry {
+    fn compare(&self, other: &SongEntry, key: SortKey) -> Ordering {
+        match key {
+            SortKey::Path => Ordering::Equal,
+            SortKey::Name => self.name.cmp(&other.name),
+            SortKey::Version => compare_versions(self.version, other.version),
+            SortKey::Tempo => self.tempo.total_cmp(&other.tempo),
+            SortKey::Chains => self.stats.used_chains.cmp(&other.stats.used_chains),
+            SortKey::Phrases => self.stats.used_phrases.cmp(&other.stats.used_phrases),
+            SortKey::Instruments => self.stats.instruments.total().cmp(&other.stats.instruments.total()),
+            SortKey::Samples => self.stats.distinct_samples.cmp(&other.stats.distinct_samples)
+        }.then_with(|| self.path.cmp(&other.path))
+    }
+
+    fn columns(&self) -> [String; 8] {
+        [
+            self.path.clone(),
+            self.name.clone(),
+            self.version.to_string(),
+            format!("{:.2}", self.tempo),
+            self.stats.used_chains.to_string(),
+            self.stats.used_phrases.to_string(),
+            self.stats.instruments.total().to_string(),
+            self.stats.distinct_samples.to_string()
+        ]
+    }
+}
+
+const HEADER : [&str; 8] = ["PATH", "NAME", "VERSION", "TEMPO", "CHAINS", "PHRASES", "INSTRUMENTS", "SAMPLES"];
+
+/// Print the rows in aligned columns, the text columns are
+/// left aligned, the numbers right aligned.
+fn print_table(rows: &[[String; 8]]) {
+    let mut widths = HEADER.map(|h| h.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: [&str; 8]| {
+        let line : Vec<String> = cells.iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(i, (cell, width))| if i < 2 {
+                format!("{:<w$}", cell, w = width)
+            } else {
+                format!("{:>w$}", cell, w = width)
+            })
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(HEADER);
+    for row in rows {
+        print_row(row.each_ref().map(|c| c.as_str()));
+    }
+}
+
+/// List the songs of the backup with their name, version, tempo and
+/// figures, sorted by one of the columns.
+pub fn ls_songs(
+    cwd: &Path,
+    pattern: &Option<String>,
+    filter: &SongFilter,
+    sort: SortKey,
+    reverse: bool,
+    excludes: &[String]) -> Result<(), M8FstoErr> {
+
+    let version = match &filter.version {
+        None => None,
+        Some(v) => {
+            let (comparison, version) = Comparison::parse_prefix(v);
+            let version = parse_version(version)
+                .ok_or_else(|| M8FstoErr::InvalidArgument {
+                    argument: v.clone(),
+                    reason: String::from("expecting a version like 6.2 or 4.1.0, optionally preceded by a comparison")
+                })?;
+            Some((comparison, version))
+        }
+    };
+
+    let name = filter.name.as_ref()
+        .map(|n| Pattern::new(n)
+            .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) }))
+        .transpose()?;
+
+    let mut entries = vec![];
+    let walked = Library::new(cwd)
+        .songs_only()
+        .include(pattern.as_slice())
+        .exclude(excludes)?
+        .select(&filter.query)?
+        .walk(|file| {
+            let M8Content::Song(song) = &file.content else { return Ok(()) };
+
+            let version_ok = version
+                .is_none_or(|(c, v)| c.holds(compare_versions(song.version, v)));
+            let name_ok = name.as_ref()
+                .is_none_or(|p| p.matches_with(&song.name, MATCH_OPTIONS));
+
+            if version_ok && name_ok {
+                entries.push(SongEntry {
+                    path: file.relative_path(cwd).display().to_string(),
+                    name: song.name.clone(),
+                    version: song.version,
+                    tempo: song.tempo,
+                    stats: SongStats::of_song(song)
+                });
+            }
+            Ok(())
+        });
+
+    entries.sort_by(|a, b| a.compare(b, sort));
+    if reverse {
+        entries.reverse();
+    }
+
+    let rows : Vec<[String; 8]> = entries.iter().map(|e| e.columns()).collect();
+    print_table(&rows);
+    println!("\n{} songs", entries.len());
+
+    walked
+}
@@ -0,0 +1,198 @@
+use std::{collections::{hash_map::Entry, HashMap}, fmt::Display, path::Path};
+
+use glob::{glob, Pattern};
+use m8_file_parser::Instrument;
+
+use crate::{
+    library::Library,
+    metadata::is_sample_file,
+    types::{combine, M8FstoErr}
+};
+
+/// Longest sample path the M8 can store, the field is 128 bytes
+/// long including the terminating zero.
+pub const MAX_SAMPLE_PATH_LENGTH : usize = 127;
+
+/// Characters forbidden in FAT file names
+const FAT_FORBIDDEN : [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// Reason a sample path cannot be used on the device
+pub enum PathIssue {
+    /// Path doesn't fit in the instrument
+    TooLong { length: usize },
+
+    /// Character the M8 cannot display or the SD card cannot store
+    UnsupportedCharacter { c: char },
+
+    /// File or folder name ending with a dot or a space, which
+    /// FAT silently drops
+    InvalidName { name: String },
+
+    /// Another path only differing by case, FAT ignores the case
+    Collision { other: String }
+}
+
+impl PathIssue {
+    /// The path cannot be written in a song at all
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, PathIssue::TooLong { .. })
+    }
+}
+
+impl Display for PathIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathIssue::TooLong { length } =>
+                write!(f, "{} bytes long, the M8 is limited to {}", length, MAX_SAMPLE_PATH_LENGTH),
+            PathIssue::UnsupportedCharacter { c } =>
+                write!(f, "unsupported character {:?}", c),
+            PathIssue::InvalidName { name } =>
+                write!(f, "\"{}\" ends with a dot or a space", name),
+            PathIssue::Collision { other } =>
+                write!(f, "same name as \"{}\" on the SD card", other)
+        }
+    }
+}
+
+/// Length and characters issues of a sample path, as written in a song
+pub fn sample_path_issues(sample_path: &str) -> Vec<PathIssue> {
+    let mut issues = vec![];
+
+    if sample_path.len() > MAX_SAMPLE_PATH_LENGTH {
+        issues.push(PathIssue::TooLong { length: sample_path.len() });
+    }
+
+    let mut seen = vec![];
+    for c in sample_path.chars() {
+        let supported = (' ' ..= '~').contains(&c) && !FAT_FORBIDDEN.contains(&c);
+        if !supported && !seen.contains(&c) {
+            seen.push(c);
+            issues.push(PathIssue::UnsupportedCharacter { c });
+        }
+    }
+
+    for name in sample_path.split('/') {
+        if name != "." && name != ".." && (name.ends_with('.') || name.ends_with(' ')) {
+            issues.push(PathIssue::InvalidName { name: name.to_string() });
+        }
+    }
+
+    issues
+}
+
+/// Key under which FAT compares a path with its siblings: the file
+/// name case is ignored.
+pub fn fat_key(path: &str) -> String {
+    match path.rsplit_once('/') {
+        None => path.to_lowercase(),
+        Some((parent, name)) => format!("{}/{}", parent, name.to_lowercase())
+    }
+}
+
+/// Paths already used on the SD card, to detect collisions
+#[derive(Default)]
+pub struct FatNames {
+    names: HashMap<String, String>
+}
+
+impl FatNames {
+    /// Register a path, returning the registered path colliding with it
+    pub fn insert(&mut self, path: &str) -> Option<String> {
+        match self.names.entry(fat_key(path)) {
+            Entry::Occupied(o) if o.get() != path => Some(o.get().clone()),
+            Entry::Occupied(_) => None,
+            Entry::Vacant(v) => {
+                v.insert(path.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Turn an issue of a path in an error
+pub fn issue_error(sample_path: &str, issue: &PathIssue) -> M8FstoErr {
+    M8FstoErr::UnusableSamplePath {
+        path: sample_path.to_string(),
+        reason: issue.to_string()
+    }
+}
+
+/// Check a sample path about to be written, issues are errors, unless
+/// `force` is set where only the fatal ones are, the others being
+/// printed as warnings.
+pub fn check_sample_path(sample_path: &str, force: bool) -> Result<(), M8FstoErr> {
+    let mut errors = None;
+
+    for issue in sample_path_issues(sample_path) {
+        if force && !issue.is_fatal() {
+            eprintln!("Warning: sample path \"{}\" : {}", sample_path, issue);
+        } else {
+            errors = combine(errors, issue_error(sample_path, &issue));
+        }
+    }
+
+    match errors {
+        None => Ok(()),
+        Some(e) => Err(e)
+    }
+}
+
+/// Report the samples of the backup, and the sample paths used by the
+/// songs and instruments, which cannot be used on the M8.
+pub fn check_paths(backup_root: &Path, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let library = Library::new(backup_root).exclude(excludes)?;
+    let root_pattern = Pattern::escape(&backup_root.to_string_lossy());
+    let mut problems = 0;
+    let mut fat_names = FatNames::default();
+
+    for entry in glob(&format!("{}/**/*", root_pattern))
+        .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) })?
+        .flatten() {
+
+        if library.is_excluded(&entry) {
+            continue;
+        }
+
+        let Ok(rel) = entry.strip_prefix(backup_root) else { continue };
+
+        let sample_path = format!("/{}", rel.to_string_lossy().replace('\\', "/"));
+        let mut issues = vec![];
+
+        if let Some(other) = fat_names.insert(&sample_path) {
+            issues.push(PathIssue::Collision { other });
+        }
+
+        if is_sample_file(&entry) {
+            issues.extend(sample_path_issues(&sample_path));
+        }
+
+        for issue in issues {
+            println!("{} : {}", sample_path, issue);
+            problems += 1;
+        }
+    }
+
+    let walked = library.walk(|file| {
+        for (ix, instr) in file.instruments() {
+            let Instrument::Sampler(sampler) = instr else { continue };
+            if sampler.sample_path.is_empty() {
+                continue;
+            }
+
+            for issue in sample_path_issues(&sampler.sample_path) {
+                println!("{} {:02X} \"{}\" : {}",
+                    file.relative_path(backup_root).display(), ix, sampler.sample_path, issue);
+                problems += 1;
+            }
+        }
+        Ok(())
+    });
+
+    if problems == 0 {
+        println!("All the sample paths can be used on the M8");
+    } else {
+        println!("\n{} problems found", problems);
+    }
+
+    walked
+}
@@ -1,11 +1,15 @@
 use std::{io::stdout, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{builder::TypedValueParser, Args, Parser, Subcommand};
 use clap_num::maybe_hex;
 use types::{FlagBag, M8FstoErr};
 
 mod ls_sample;
+mod ls_songs;
 mod grep_sample;
+mod grep_instrument;
+mod grep_fx;
+mod grep_melody;
 mod bundle;
 mod prune_bundle;
 mod broken_search;
@@ -14,6 +18,23 @@ mod show_song;
 mod move_samples;
 mod renumber;
 mod chord_gen;
+mod wav;
+mod aiff;
+mod convert_samples;
+mod sample_memory;
+mod sampler_gen;
+mod slice;
+mod kit_builder;
+mod audition;
+mod name_instruments;
+mod tune_samples;
+mod journal;
+mod library;
+mod m8_path;
+mod remove_samples;
+mod ignore;
+mod query;
+mod metadata;
 
 #[derive(Parser)]
 #[command(version, about, long_about=None)]
@@ -26,6 +47,24 @@ struct Cli {
     command: Option<M8Commands>
 }
 
+/// Files and folders skipped by the commands searching the backup
+#[derive(Args)]
+struct ExcludeArgs {
+    /// Skip the files and folders matching this pattern, written
+    /// like in the `.m8fstoignore` file, can be repeated.
+    #[arg(short = 'x', long = "exclude", value_name = "PATTERN")]
+    patterns: Vec<String>
+}
+
+/// Songs selected by the commands walking many songs
+#[derive(Args)]
+struct QueryArgs {
+    /// Only the songs matching this query on their metadata
+    /// (e.g. "tempo > 140 and uses_instrument(HyperSynth)")
+    #[arg(short = 'w', long = "where", value_name = "QUERY")]
+    query: Option<String>
+}
+
 /// What do we want to print, prefix with 0x to use hexadecimal notation.
 #[derive(Subcommand)]
 enum ShowTarget {
@@ -33,8 +72,18 @@ enum ShowTarget {
     Song,
 
     /// Display various information about the song like version,
-    /// number of instruments, number of used samples
-    Info,
+    /// number of instruments, number of used samples and the
+    /// sample memory used.
+    Info {
+        /// Root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Sample memory of the device in megabytes
+        #[arg(short, long, default_value_t=sample_memory::DEFAULT_MEMORY_LIMIT_MB)]
+        limit: f64
+    },
 
     /// Display mixer settings
     Mixer,
@@ -78,7 +127,8 @@ struct ShowCommand {
     #[structopt(subcommand)]
     pub show_command: ShowTarget,
 
-    /// File to display
+    /// File to display, `info` also takes a folder or a glob
+    /// pattern to display many songs.
     pub file: String
 }
 
@@ -119,27 +169,150 @@ enum M8Commands {
     /// Renumber an element of the M8
     Renumber(RenumberCommand),
 
-    /// List samples used in M8 song file
+    /// List samples used in M8 song or instrument files
     LsSample {
+        /// Inspect the sample files: format, duration, size
+        /// and problems for the M8.
+        #[arg(short, long)]
+        details: bool,
+
+        /// Root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
         /// Optional path/folder
         path: Option<String>
     },
 
+    /// List the songs of the backup, with their name, version, tempo,
+    /// used chains and phrases, instrument and sample counts
+    LsSongs {
+        /// Sort the songs by this column
+        #[arg(short, long, value_enum, default_value_t = ls_songs::SortKey::Path)]
+        sort: ls_songs::SortKey,
+
+        /// Sort in descending order
+        #[arg(short, long)]
+        reverse: bool,
+
+        /// Only list the songs with this version, can be preceded
+        /// by a comparison (e.g. '<6.0', '>=4.1')
+        #[arg(short, long)]
+        version: Option<String>,
+
+        /// Only list the songs with this name, glob pattern
+        /// ignoring the case (e.g. 'DUB*')
+        #[arg(short, long)]
+        name: Option<String>,
+
+        #[command(flatten)]
+        query: QueryArgs,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// Songs to list, folder or glob pattern, by default every
+        /// song under the current directory.
+        path: Option<String>
+    },
+
     Chords,
 
-    /// Try to find songs that are using a given sample
+    /// Try to find songs and instruments that are using a given sample,
+    /// only songs are searched with `--where`.
     GrepSample {
         /// Pattern to search, representing a sample file path using
         /// glob patterns
         pattern : String,
 
+        #[command(flatten)]
+        query: QueryArgs,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
         /// In which folder to search
         path : Option<String>
     },
 
+    /// Try to find songs and instruments having instruments with
+    /// the given properties
+    GrepInstrument {
+        /// Instrument type
+        #[arg(short = 't', long = "type", ignore_case = true,
+              value_parser = clap::builder::PossibleValuesParser::new(metadata::INSTRUMENT_KINDS))]
+        kind: Option<String>,
+
+        /// Instrument name, glob pattern ignoring the case
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// MIDI channel of MIDI out and external instruments, as
+        /// displayed by `show`.
+        #[arg(short, long, value_parser=maybe_hex::<u8>)]
+        channel: Option<u8>,
+
+        /// Shape of macrosynth and wavsynth instruments, glob pattern
+        /// ignoring the case (e.g. 'SAW*')
+        #[arg(short, long)]
+        shape: Option<String>,
+
+        /// Algorithm number of FM synth instruments
+        #[arg(short, long, value_parser=maybe_hex::<u8>)]
+        algo: Option<u8>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// In which folder to search
+        path : Option<String>
+    },
+
+    /// Find the phrase and table steps using an FX command
+    GrepFx {
+        /// Command name, as displayed by the device (e.g. DEL, TBX)
+        command: String,
+
+        /// Optional command value, in hexadecimal
+        #[arg(value_parser=grep_fx::parse_fx_value)]
+        value: Option<u8>,
+
+        /// In which folder to search
+        #[arg(short, long)]
+        path: Option<String>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
+    /// Find the phrases playing a melody, given as notes (C-4 D#4 G-4)
+    /// or as intervals in semitones (+3 +4)
+    GrepMelody {
+        /// Notes or intervals of the melody
+        #[arg(required = true, num_args = 1.., allow_negative_numbers = true)]
+        melody: Vec<String>,
+
+        /// Search the notes at any pitch, only the intervals
+        /// between the notes must match.
+        #[arg(short, long)]
+        transposed: bool,
+
+        /// In which folder to search
+        #[arg(short, long)]
+        path: Option<String>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
     /// Bundle a song, avoiding sample duplication
     Bundle {
-        /// Specific song only
+        /// Song to bundle, can be a glob pattern matching
+        /// many songs
         song : String,
 
         /// Root folder for the sample path.
@@ -147,7 +320,25 @@ enum M8Commands {
 
         /// Where to write the bundled song, by default
         /// will be in the root directory "Bundle" subfolder.
-        out_folder: Option<String>
+        out_folder: Option<String>,
+
+        /// Extra songs (or glob patterns) to bundle along the
+        /// first one.
+        #[arg(short, long)]
+        add: Vec<String>,
+
+        /// Name of the set folder when bundling many songs together,
+        /// all the songs share a single sample pool.
+        #[arg(short, long)]
+        set: Option<String>,
+
+        /// Only keep the part of the samples played by the sampler
+        /// instruments, rescaling their start/loop/length.
+        #[arg(short, long)]
+        crop: bool,
+
+        #[command(flatten)]
+        query: QueryArgs
     },
 
     /// Given a bundled song, remove all local samples
@@ -167,13 +358,21 @@ enum M8Commands {
         /// When searching direct song, which root do we use?
         root: Option<String>,
 
+        #[command(flatten)]
+        query: QueryArgs,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
         /// Optional paths to process: directories or `.m8s` song files.
         /// If not set, the current working directory is used.
         paths: Vec<String>,
     },
 
-    /// Move a sample or sample folder and update songs referencing
-    /// them.
+    /// Move samples or sample folders and update songs referencing
+    /// them. With `--where`, only the sources used by the matching songs
+    /// are moved: a folder is moved whole as soon as one of its samples is
+    /// used. Every song is still updated.
     Mv {
         /// If set, it will list the sample to be moved
         /// and the list of modified songs & instruments
@@ -190,11 +389,309 @@ enum M8Commands {
         #[arg(short, long)]
         root: Option<String>,
 
-        /// Source folder or sample
-        from: String,
+        /// Source folders or samples, can be glob patterns. With several
+        /// sources, each one is moved inside the destination folder.
+        #[arg(required = true, num_args = 1..)]
+        from: Vec<String>,
 
         /// Destination
-        to: String
+        to: String,
+
+        #[command(flatten)]
+        query: QueryArgs,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
+    /// Convert samples in formats unsupported or wasteful for the M8
+    /// (AIFF, floating point, high sample rates) to PCM WAV, and update
+    /// the songs using them. Originals are kept in a quarantine folder.
+    ConvertSamples {
+        /// If set, only list the samples to be converted
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// If set, songs will be written even if some
+        /// songs cannot be rewritten (like in 3.x format)
+        #[arg(short, long)]
+        force : bool,
+
+        /// Optional root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Bit depth of the converted samples, by default 16 bits
+        /// samples stay 16 bits, others are converted to 24 bits.
+        #[arg(short, long, value_parser=clap::builder::PossibleValuesParser::new(["16", "24"]).map(|s| s.parse::<u16>().unwrap()))]
+        bits: Option<u16>,
+
+        /// Downmix stereo samples to mono
+        #[arg(short, long)]
+        mono: bool,
+
+        /// Resample all the samples to this rate, by default only
+        /// samples above 48kHz are resampled to 44.1kHz.
+        #[arg(long)]
+        rate: Option<u32>,
+
+        /// Delete the original samples kept in the quarantine
+        /// folder by previous conversions.
+        #[arg(long)]
+        confirm: bool,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
+    /// List the samples and sample paths the M8 cannot use: too
+    /// long, with unsupported characters or colliding on the SD card.
+    CheckPaths {
+        /// Optional root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
+    /// Compute the sample memory used by every song, and list
+    /// the songs too big to be loaded on the device.
+    MemoryCheck {
+        /// Optional root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Sample memory of the device in megabytes
+        #[arg(short, long, default_value_t=sample_memory::DEFAULT_MEMORY_LIMIT_MB)]
+        limit: f64,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// Songs to check, glob pattern, by default every
+        /// song under the root.
+        path: Option<String>
+    },
+
+    /// Cut a WAV sample in slices, written as cue markers used
+    /// by the sampler.
+    Slice {
+        /// Sample to slice
+        sample: String,
+
+        /// Where to write the sliced sample, by default next to
+        /// the sample with a "_sliced" suffix.
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Cut in this number of slices of the same length instead
+        /// of detecting transients.
+        #[arg(short, long, value_parser=clap::value_parser!(u8).range(1..=slice::MAX_SLICES as i64))]
+        count: Option<u8>,
+
+        /// Minimum energy rise, in dB, to detect a transient.
+        #[arg(short, long, default_value_t=6.0)]
+        threshold: f64,
+
+        /// Also write a sampler instrument (.m8i) next to the sliced
+        /// sample, using the slices.
+        #[arg(short, long)]
+        instrument: bool,
+
+        /// Root folder of the backup, used to write the sample path
+        /// of the instrument, if not set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>
+    },
+
+    /// Create a sampler instrument (.m8i) for every sample of a folder
+    KitBuilder {
+        /// Folder containing the samples
+        dir: String,
+
+        /// Folder where the instruments are written
+        #[arg(short, long)]
+        out: String,
+
+        /// Root folder of the backup, used to write the sample path
+        /// of the instruments, if not set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Song (v4 or above) used as a template, the instruments are
+        /// loaded in consecutive slots and the song is written in the
+        /// kit folder.
+        #[arg(short, long)]
+        song: Option<String>,
+
+        /// First instrument slot used in the song template
+        #[arg(long, default_value_t=0, value_parser=maybe_hex::<usize>)]
+        first_slot: usize
+    },
+
+    /// Generate songs to audition samples on the device, one sampler
+    /// instrument, phrase, chain and song row per sample.
+    Audition {
+        /// Samples to audition, glob pattern
+        pattern: String,
+
+        /// Song (v4 or above) used as a base, an empty song
+        /// saved on the device is best.
+        #[arg(short, long)]
+        song: String,
+
+        /// Folder where the songs are written, by default
+        /// the current directory.
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Name of the generated song files, suffixed by a number
+        /// when more than 128 samples are auditioned.
+        #[arg(short, long, default_value="AUDITION")]
+        name: String,
+
+        /// Root folder of the backup, used to write the sample paths,
+        /// if not set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>
+    },
+
+    /// Name the unnamed sampler instruments from their sample file
+    /// name, in songs and instrument files.
+    NameInstruments {
+        /// If set, only list the instruments to be renamed
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// Also rename the instruments already having a name
+        #[arg(short, long)]
+        overwrite: bool,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// Song or instrument file, or glob pattern, by default all the
+        /// songs and instruments under the current directory.
+        path: Option<String>
+    },
+
+    /// Detect the pitch of the samples used by sampler instruments, and
+    /// suggest or apply the tuning to play them in tune from C-4.
+    TuneSamples {
+        /// Write the PITCH and FINE values in the instruments
+        #[arg(short, long)]
+        apply: bool,
+
+        /// If set with apply, only list the tuning
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// Pitches found with a lower confidence (between 0 and 1)
+        /// are not applied.
+        #[arg(short, long, default_value_t=0.8)]
+        min_confidence: f64,
+
+        /// Root folder for the sample path, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// Song or instrument file, or glob pattern, by default all the
+        /// songs and instruments under the root.
+        path: Option<String>
+    },
+
+    /// Rewrite the sample paths of songs and instruments for samples
+    /// already moved, without moving any file.
+    Relink {
+        /// If set, only list the instruments to be rewritten
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// Rewrite even if some new samples don't exist, or some
+        /// songs cannot be rewritten.
+        #[arg(short, long)]
+        force : bool,
+
+        /// Optional root folder of the backup, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Old sample path or folder, as written in the songs
+        /// (e.g. /Samples/Packs/Elektron)
+        old_prefix: String,
+
+        /// New sample path or folder, from the backup root
+        new_prefix: String,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs
+    },
+
+    /// Remove a sample or sample folder, refusing if songs or instruments
+    /// still use it.
+    Rm {
+        /// If set, only list the instruments using the samples
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// Remove even if instruments use the samples, their sample
+        /// is cleared.
+        #[arg(short, long)]
+        force : bool,
+
+        /// Optional root folder of the backup, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>,
+
+        /// Sample used instead by the instruments using the removed
+        /// samples.
+        #[arg(long)]
+        replace: Option<String>,
+
+        /// Remove even if some songs or instruments cannot be read,
+        /// they may still use the samples.
+        #[arg(long)]
+        ignore_unreadable: bool,
+
+        /// Delete for good the files removed by previous `rm`
+        /// and kept in the trash folder.
+        #[arg(long, conflicts_with = "target")]
+        empty_trash: bool,
+
+        #[command(flatten)]
+        exclude: ExcludeArgs,
+
+        /// Sample or folder to remove
+        #[arg(required_unless_present = "empty_trash")]
+        target: Option<String>
+    },
+
+    /// Undo the last `mv`, `relink`, `rm`, `convert-samples`, `name-instruments` or
+    /// `tune-samples`, restoring the rewritten songs and instruments and moving
+    /// back the samples.
+    Undo {
+        /// If set, only list the changes to be undone
+        #[arg(short, long)]
+        dry_run : bool,
+
+        /// Undo even if some files changed since the operation
+        #[arg(short, long)]
+        force : bool,
+
+        /// Optional root folder of the backup, if not
+        /// set, current working directory is used.
+        #[arg(short, long)]
+        root: Option<String>
     }
 }
 
@@ -218,24 +715,44 @@ fn main() {
             print_errors(renumber::renumber_element(recommand, &mut stdout()));
         }
         Some(M8Commands::Show(showcmd)) => {
-            print_errors(show_song::show_element(showcmd, &mut stdout()));
+            print_errors(show_song::show_element(showcmd, cwd.as_path(), &mut stdout()));
+        }
+        Some(M8Commands::LsSample { details, root, exclude, path }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            let details = if details { Some(root.as_path()) } else { None };
+            print_errors(ls_sample::ls_sample(cwd.as_path(), details, &path, &exclude.patterns))
         }
-        Some(M8Commands::LsSample { path }) => {
-            print_errors(ls_sample::ls_sample(cwd.as_path(), &path))
+        Some(M8Commands::LsSongs { sort, reverse, version, name, query, exclude, path }) => {
+            let filter = ls_songs::SongFilter { version, name, query: query.query };
+            print_errors(ls_songs::ls_songs(cwd.as_path(), &path, &filter, sort, reverse, &exclude.patterns))
         }
-        Some(M8Commands::GrepSample { pattern, path }) => {
-            print_errors(grep_sample::grep_sample(cwd.as_path(), &pattern, &path))
+        Some(M8Commands::GrepSample { pattern, query, exclude, path }) => {
+            print_errors(grep_sample::grep_sample(cwd.as_path(), &pattern, &path, &query.query, &exclude.patterns))
         }
-        Some(M8Commands::BrokenSearch { root, paths }) => {
+        Some(M8Commands::GrepFx { command, value, path, exclude }) => {
+            print_errors(grep_fx::grep_fx(cwd.as_path(), &command, value, &path, &exclude.patterns))
+        }
+        Some(M8Commands::GrepMelody { melody, transposed, path, exclude }) => {
+            print_errors(grep_melody::Melody::parse(&melody, transposed)
+                .and_then(|melody| grep_melody::grep_melody(cwd.as_path(), &melody, &path, &exclude.patterns)))
+        }
+        Some(M8Commands::GrepInstrument { kind, name, channel, shape, algo, exclude, path }) => {
+            let query = grep_instrument::InstrumentQuery { kind, name, channel, shape, algo };
+            print_errors(grep_instrument::grep_instrument(cwd.as_path(), &query, &path, &exclude.patterns))
+        }
+        Some(M8Commands::BrokenSearch { root, query, exclude, paths }) => {
             let root =
-                root.map_or_else(|| cwd.clone(), |e| PathBuf::from(e));
-            print_errors(broken_search::process_paths(&root, &paths))
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            print_errors(broken_search::process_paths(&root, &paths, &query.query, &exclude.patterns))
         }
-        Some(M8Commands::Bundle { song, root, out_folder }) => {
+        Some(M8Commands::Bundle { song, root, out_folder, add, set, crop, query }) => {
             let root =
-                root.map_or_else(|| cwd.clone(), |e| PathBuf::from(e));
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
 
-            print_errors(bundle::bundle_song(root.as_path(), &song, &out_folder))
+            let mut songs = vec![song];
+            songs.extend(add);
+            print_errors(bundle::bundle_song(root.as_path(), &songs, &set, &out_folder, crop, &query.query))
         }
         Some(M8Commands::PruneBundle { dry_run, song}) => {
             let flags = FlagBag {
@@ -246,11 +763,63 @@ fn main() {
 
             print_errors(prune_bundle::prune_bundle(flags, &song))
         },
-        Some(M8Commands::Mv { root, force, dry_run, from, to }) => {
+        Some(M8Commands::Mv { root, force, dry_run, from, to, query, exclude }) => {
             let root = root
                 .map_or_else(
                     || cwd.as_path().to_path_buf(),
-                     |f| PathBuf::from(f));
+                    PathBuf::from);
+
+            let flags = FlagBag {
+                dry_run,
+                force,
+                verbose: false
+            };
+
+            print_errors(move_samples::move_samples(&root, flags, from, to, &query.query, &exclude.patterns));
+        }
+        Some(M8Commands::Relink { dry_run, force, root, old_prefix, new_prefix, exclude }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+
+            let flags = FlagBag {
+                dry_run,
+                force,
+                verbose: false
+            };
+
+            print_errors(move_samples::relink(&root, flags, &old_prefix, &new_prefix, &exclude.patterns));
+        }
+        Some(M8Commands::Rm { dry_run, force, root, replace, ignore_unreadable, empty_trash: _, exclude, target }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+
+            let flags = FlagBag {
+                dry_run,
+                force,
+                verbose: false
+            };
+
+            match target {
+                Some(target) =>
+                    print_errors(remove_samples::remove_samples(&root, flags, &target, &replace, ignore_unreadable, &exclude.patterns)),
+                None => print_errors(remove_samples::empty_trash(&move_samples::normalize_path(&root), &flags))
+            }
+        }
+        Some(M8Commands::Undo { dry_run, force, root }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+
+            let flags = FlagBag {
+                dry_run,
+                force,
+                verbose: false
+            };
+
+            print_errors(journal::undo(&move_samples::normalize_path(&root), flags));
+        }
+        Some(M8Commands::ConvertSamples { dry_run, force, root, bits, mono, rate, confirm, exclude }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
 
             let flags = FlagBag {
                 dry_run,
@@ -258,7 +827,61 @@ fn main() {
                 verbose: false
             };
 
-            print_errors(move_samples::move_samples(&root, flags, from, to));
+            let options = convert_samples::ConvertOptions { bits, mono, rate };
+            print_errors(convert_samples::convert_samples(&root, flags, options, confirm, &exclude.patterns));
+        }
+        Some(M8Commands::CheckPaths { root, exclude }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            print_errors(m8_path::check_paths(&move_samples::normalize_path(&root), &exclude.patterns))
+        }
+        Some(M8Commands::MemoryCheck { root, limit, exclude, path }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            let limit = sample_memory::limit_of_megabytes(limit);
+            print_errors(sample_memory::check_memory(&root, &path, limit, &exclude.patterns))
+        }
+        Some(M8Commands::Slice { sample, out, count, threshold, instrument, root }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            let mode = match count {
+                Some(count) => slice::SliceMode::Equal { count: count as usize },
+                None => slice::SliceMode::Transients { threshold_db: threshold }
+            };
+            print_errors(slice::slice_sample(&root, &PathBuf::from(sample), &out, mode, instrument))
+        }
+        Some(M8Commands::KitBuilder { dir, out, root, song, first_slot }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            print_errors(kit_builder::build_kit(&root, &PathBuf::from(dir), &PathBuf::from(out), &song, first_slot))
+        }
+        Some(M8Commands::Audition { pattern, song, out, name, root }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+            let out = out.map_or_else(|| cwd.clone(), PathBuf::from);
+            print_errors(audition::audition(&root, &pattern, &PathBuf::from(song), &out, &name))
+        }
+        Some(M8Commands::NameInstruments { dry_run, overwrite, exclude, path }) => {
+            let flags = FlagBag {
+                dry_run,
+                force: false,
+                verbose: false
+            };
+
+            print_errors(name_instruments::name_instruments(&cwd, flags, overwrite, &path, &exclude.patterns))
+        }
+        Some(M8Commands::TuneSamples { apply, dry_run, min_confidence, root, exclude, path }) => {
+            let root =
+                root.map_or_else(|| cwd.clone(), PathBuf::from);
+
+            let flags = FlagBag {
+                dry_run,
+                force: false,
+                verbose: false
+            };
+
+            let options = tune_samples::TuneOptions { apply, min_confidence };
+            print_errors(tune_samples::tune_samples(&move_samples::normalize_path(&root), flags, options, &path, &exclude.patterns))
         }
     }
 }
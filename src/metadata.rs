@@ -0,0 +1,206 @@
+use std::{cmp::Ordering, collections::HashSet, fmt::Display, path::Path};
+use m8_file_parser::{Instrument, Song, Version};
+
+/// File extensions considered as samples
+pub const SAMPLE_EXTENSIONS : [&str; 3] = ["wav", "aif", "aiff"];
+
+/// Is the file a sample that can be loaded by the sampler
+pub fn is_sample_file(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| SAMPLE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Instrument types, as named by `m8fsto show` and the device
+pub const INSTRUMENT_KINDS : [&str; 7] =
+    ["WavSynth", "MacroSynth", "Sample", "MIDIOut", "FMSynth", "HyperSynth", "External"];
+
+/// Type of the instrument, one of `INSTRUMENT_KINDS`
+pub fn instrument_kind(instr: &Instrument) -> &'static str {
+    match instr {
+        Instrument::WavSynth(_) => "WavSynth",
+        Instrument::MacroSynth(_) => "MacroSynth",
+        Instrument::Sampler(_) => "Sample",
+        Instrument::MIDIOut(_) => "MIDIOut",
+        Instrument::FMSynth(_) => "FMSynth",
+        Instrument::HyperSynth(_) => "HyperSynth",
+        Instrument::External(_) => "External",
+        Instrument::None => "None"
+    }
+}
+
+/// Name of the instrument, `Instrument::name` misses the MIDI out name
+pub fn instrument_name(instr: &Instrument) -> &str {
+    match instr {
+        Instrument::MIDIOut(midi) => &midi.name,
+        _ => instr.name().unwrap_or("")
+    }
+}
+
+/// MIDI channel of MIDI out and external instruments
+pub fn midi_channel(instr: &Instrument) -> Option<u8> {
+    match instr {
+        Instrument::MIDIOut(midi) => Some(midi.channel),
+        Instrument::External(ext) => Some(ext.channel),
+        _ => None
+    }
+}
+
+#[derive(Default)]
+pub struct InstrumentCounter {
+    pub wavsynth_count : usize,
+    pub macrosynth_count : usize,
+    pub fm_count : usize,
+    pub sampler_count : usize,
+    pub midi_count : usize,
+    pub external_count : usize,
+    pub hypersynth_count: usize,
+
+    pub used_midi_channel : HashSet<u8>
+}
+
+impl InstrumentCounter {
+    pub fn total(&self) -> usize {
+        self.wavsynth_count +
+            self.macrosynth_count +
+            self.fm_count +
+            self.sampler_count +
+            self.midi_count +
+            self.external_count +
+            self.hypersynth_count
+    }
+
+    pub fn count(mut self, instr: &Instrument) -> Self {
+        match instr {
+            Instrument::None => (),
+            Instrument::WavSynth(_) => self.wavsynth_count += 1,
+            Instrument::MacroSynth(_) => self.macrosynth_count += 1,
+            Instrument::Sampler(_) => self.sampler_count += 1,
+            Instrument::MIDIOut(_) => self.midi_count += 1,
+            Instrument::FMSynth(_) => self.fm_count += 1,
+            Instrument::HyperSynth(_) => self.hypersynth_count += 1,
+            Instrument::External(_) => self.external_count += 1,
+        };
+
+        if let Some(channel) = midi_channel(instr) {
+            self.used_midi_channel.insert(channel);
+        }
+
+        self
+    }
+}
+
+impl Display for InstrumentCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Instruments count    : {}", self.total())?;
+        writeln!(f, "            Wavsynth : {}", self.wavsynth_count)?;
+        writeln!(f, "          Macrosynth : {}", self.macrosynth_count)?;
+        writeln!(f, "             Sampler : {}", self.sampler_count)?;
+        writeln!(f, "             FmSynth : {}", self.fm_count)?;
+        writeln!(f, "          HyperSynth : {}", self.hypersynth_count)?;
+        writeln!(f, "            MIDI out : {}", self.midi_count)?;
+        writeln!(f, "           Ext instr : {}", self.external_count)?;
+
+        let midi_vec : Vec<_> = self.used_midi_channel.iter().map(|c| format!("{}", c)).collect();
+        write!(f, "  used midi channels : {}", midi_vec.join(", "))?;
+        Ok(())
+    }
+}
+
+/// Figures of a song, displayed by `show info` and `ls-songs`
+pub struct SongStats {
+    pub instruments: InstrumentCounter,
+    pub distinct_samples: usize,
+    pub non_flat_eqs: usize,
+    pub non_empty_tables: usize,
+    pub used_chains: usize,
+    pub used_phrases: usize
+}
+
+impl SongStats {
+    pub fn of_song(s: &Song) -> SongStats {
+        let samples : HashSet<_> = s.instruments
+            .iter()
+            .filter_map(|i|
+                match i {
+                    Instrument::Sampler(s) => Some(s.sample_path.clone()),
+                    _ => None
+                })
+            .collect();
+
+        SongStats {
+            instruments: s.instruments.iter()
+                .fold(
+                    InstrumentCounter::default(),
+                    |acc, i| acc.count(i)),
+            distinct_samples: samples.len(),
+            non_flat_eqs: s.eqs.iter().filter(|t| !t.is_empty()).count(),
+            non_empty_tables: s.tables.iter().filter(|t| !t.is_empty()).count(),
+            used_chains: s.chains.iter().filter(|c| !c.is_empty()).count(),
+            used_phrases: s.phrases.iter().filter(|c| !c.is_empty()).count()
+        }
+    }
+}
+
+/// Comparison operator of a filter
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Lower,
+    LowerOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    Different
+}
+
+impl Comparison {
+    /// Split the operator at the start of a filter, equality if none
+    pub fn parse_prefix(text: &str) -> (Comparison, &str) {
+        let text = text.trim_start();
+        let operators = [
+            ("<=", Comparison::LowerOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("!=", Comparison::Different),
+            ("==", Comparison::Equal),
+            ("<", Comparison::Lower),
+            (">", Comparison::Greater),
+            ("=", Comparison::Equal)
+        ];
+
+        for (op, comparison) in operators {
+            if let Some(rest) = text.strip_prefix(op) {
+                return (comparison, rest.trim())
+            }
+        }
+
+        (Comparison::Equal, text.trim())
+    }
+
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Lower => ordering == Ordering::Less,
+            Comparison::LowerOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::Different => ordering != Ordering::Equal
+        }
+    }
+}
+
+/// Parse a song version, the missing parts are 0 (`6.2` is `6.2.0`)
+pub fn parse_version(text: &str) -> Option<Version> {
+    let mut parts = text.trim().split('.').map(|p| p.parse::<u8>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+
+    match parts.next() {
+        None => Some(Version { major, minor, patch }),
+        Some(_) => None
+    }
+}
+
+pub fn compare_versions(a: Version, b: Version) -> Ordering {
+    (a.major, a.minor, a.patch).cmp(&(b.major, b.minor, b.patch))
+}
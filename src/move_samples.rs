@@ -1,15 +1,21 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::path::Component;
 use glob::glob;
-use m8_file_parser::{reader::*, Instrument};
+use m8_file_parser::Instrument;
 
+use crate::broken_search::sample_to_absolute_path;
+use crate::journal::Transaction;
+use crate::library::{Library, M8File};
+use crate::m8_path::{check_sample_path, issue_error, FatNames, PathIssue, MAX_SAMPLE_PATH_LENGTH};
+use crate::metadata::is_sample_file;
 use crate::types::combine;
 use crate::types::FlagBag;
 use crate::types::M8FstoErr;
 
-enum Swap {
+pub(crate) enum Swap {
     Dir { from: String, to: String },
     File { from: String, to: String }
 }
@@ -67,36 +73,39 @@ struct SwappedFile {
     touched: Vec<SwappedInstruments>
 }
 
-fn on_file_blob(flags: &FlagBag, swap: &Swap, path: &Path, data: Vec<u8>) -> Result<Option<SwappedFile>, M8FstoErr> {
-    let mut reader = Reader::new(data.clone());
-    let mut touched = vec![];
-    let mut song = m8_file_parser::Song::read_from_reader(&mut reader)
-        .map_err(|e| M8FstoErr::UnparseableM8File {
-            path: path.to_path_buf(),
-            reason: format!("{:?}", e)
-        })?;
+/// Apply the first matching swap to a sampler instrument
+fn swap_instrument(swaps: &[Swap], instrument: usize, instr: &mut Instrument) -> Option<SwappedInstruments> {
+    let Instrument::Sampler(sampler) = instr else { return None };
+    let new_path = swaps.iter().find_map(|swap| swap.try_swap(&sampler.sample_path))?;
 
-    for (instrument, instr) in song.instruments.iter_mut().enumerate() {
-        match instr {
-            Instrument::Sampler(sampler) => {
-                match swap.try_swap(&sampler.sample_path) {
-                    None => {}
-                    Some(new_path) => {
-                        touched.push(SwappedInstruments {
-                            instrument,
-                            instrument_name: sampler.name.clone(),
-                            original_sample_path: sampler.sample_path.clone(),
-                            new_sample_path: new_path.clone()
-                        });
-                        sampler.sample_path = new_path;
-                    }
-                }
-            }
-            _ => {}
-        }
+    let swapped = SwappedInstruments {
+        instrument,
+        instrument_name: sampler.name.clone(),
+        original_sample_path: sampler.sample_path.clone(),
+        new_sample_path: new_path.clone()
+    };
+
+    sampler.sample_path = new_path;
+    Some(swapped)
+}
+
+/// Refuse sample paths that cannot be written in the instrument
+fn check_length(touched: &SwappedInstruments) -> Result<(), M8FstoErr> {
+    let length = touched.new_sample_path.len();
+    if length > MAX_SAMPLE_PATH_LENGTH {
+        return Err(issue_error(&touched.new_sample_path, &PathIssue::TooLong { length }))
     }
+    Ok(())
+}
+
+fn on_file(flags: &FlagBag, swaps: &[Swap], mut file: M8File) -> Result<Option<SwappedFile>, M8FstoErr> {
+    let touched : Vec<_> = file.instruments_mut()
+        .into_iter()
+        .filter_map(|(instrument, instr)| swap_instrument(swaps, instrument, instr))
+        .collect();
 
-    if touched.len() == 0 { return Ok(None);}
+    if touched.is_empty() { return Ok(None);}
+    touched.iter().try_for_each(check_length)?;
 
     if flags.dry_run {
         return Ok(Some(SwappedFile {
@@ -105,86 +114,62 @@ fn on_file_blob(flags: &FlagBag, swap: &Swap, path: &Path, data: Vec<u8>) -> Res
         }))
     }
 
-    let mut writer =
-        m8_file_parser::writer::Writer::new(data);
-
-    song.write(&mut writer)
-        .map_err(|reason|
-            M8FstoErr::SongSerializationError {
-                reason,
-                destination: format!("{:?}", path)
-            })?;
-
     Ok(Some(SwappedFile {
-        file_data: writer.finish(),
+        file_data: file.serialize()?,
         touched
     }))
 }
 
-fn on_dir(flags: &FlagBag, cwd: &Path, swap: &Swap) -> Result<(), M8FstoErr> {
-    let mut errors = None;
-    let mut matched_not_serializable = vec![];
-    let mut to_write= vec![];
-    let search_pattern =
-        glob(&format!("{}/**/*.m8s", cwd.to_str().unwrap()))
-        .expect("Failed to read glob pattern");
-
-    for entry in search_pattern {
-        match entry {
-            Err(e) => println!("{:?}", e),
-            Ok(path) => {
-                let try_as_file = fs::read(&path);
-                match try_as_file {
-                    Err(e) => {
-                        errors = combine(errors, M8FstoErr::CannotReadFile {
-                            path: path.to_path_buf(),
-                            reason: format!("{:?}", e)
-                        })
-                    }
-                    Ok(file_blob) => {
-                        match on_file_blob(flags, swap, &path, file_blob) {
-                            Ok(None) => {}
-                            Err(M8FstoErr::SongSerializationError { reason: _, destination}) =>
-                                matched_not_serializable.push(destination),
-                            Err(m8err) =>
-                                errors = combine(errors, m8err),
-                            Ok(Some(swapped)) => {
-                                for touched in swapped.touched {
-                                    touched.print()
-                                }
-
-                                to_write.push((path, swapped.file_data));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
+/// Songs and instruments rewritten with the swaps, not yet written
+pub(crate) struct Staged {
+    /// Files to write with their new content
+    pub to_write: Vec<(PathBuf, Vec<u8>)>,
+
+    /// Files using the swapped samples, but that cannot be rewritten
+    pub not_serializable: Vec<String>,
 
-    // If we have some file we can't translate, but still want to write
-    // the files
-    if !flags.dry_run && (matched_not_serializable.len() == 0 || flags.force) {
-        for (path, data) in to_write {
-            match fs::write(&path, data) {
-                Ok(()) => {}
-                Err(_) => {
-                    errors = combine(errors, 
-                        M8FstoErr::SongSerializationError {
-                            reason: "Error while writing file".into(),
-                            destination: format!("{:?}", path)
-                        });
+    /// Sample paths written in the rewritten files
+    pub new_sample_paths: Vec<String>,
+
+    pub errors: Option<M8FstoErr>
+}
+
+/// Compute the new content of all the songs and instruments of the library
+/// using the sample path swaps, the first matching swap is applied.
+pub(crate) fn stage(flags: &FlagBag, cwd: &Path, library: &Library, swaps: &[Swap]) -> Staged {
+    let mut staged = Staged {
+        to_write: vec![],
+        not_serializable: vec![],
+        new_sample_paths: vec![],
+        errors: None
+    };
+
+    let walked = library.walk(|file| {
+        let path = file.path.clone();
+        match on_file(flags, swaps, file) {
+            Ok(None) => {}
+            Err(M8FstoErr::SongSerializationError { reason: _, destination}) =>
+                staged.not_serializable.push(destination),
+            Err(m8err) => return Err(m8err),
+            Ok(Some(swapped)) => {
+                println!("{}", path.strip_prefix(cwd).unwrap_or(&path).display());
+                for touched in swapped.touched {
+                    touched.print();
+                    staged.new_sample_paths.push(touched.new_sample_path);
                 }
+
+                staged.to_write.push((path, swapped.file_data));
             }
         }
-    }
+        Ok(())
+    });
 
-    match errors {
-        None => Ok(()),
-        Some(errs) => Err(errs)
+    if let Err(e) = walked {
+        staged.errors = Some(e);
     }
-}
 
+    staged
+}
 
 pub fn normalize_path(path: &Path) -> PathBuf {
     let mut components = path.components().peekable();
@@ -214,86 +199,364 @@ pub fn normalize_path(path: &Path) -> PathBuf {
 }
 
 
+/// Is the source argument a glob pattern rather than a path
+fn is_glob_pattern(source: &str) -> bool {
+    source.contains(['*', '?', '['])
+}
+
+/// Absolute path of an argument, relative paths are taken from the backup root
+pub(crate) fn in_backup(cwd: &Path, path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_relative() {
+        normalize_path(&cwd.join(path))
+    } else {
+        normalize_path(&path)
+    }
+}
+
+/// Path of a file of the backup as written in the songs
+pub(crate) fn to_sample_path(cwd: &Path, path: &Path) -> Result<String, M8FstoErr> {
+    let rel = path.strip_prefix(cwd)
+        .map_err(|_| M8FstoErr::InvalidPath { reason: format!("{:?} is not in the backup {:?}", path, cwd) })?;
+
+    Ok(format!("/{}", rel.to_string_lossy().replace('\\', "/")))
+}
+
+/// Expand the glob patterns of the sources, every source must exist.
+/// Ignored files and folders are not matched by the patterns.
+fn expand_sources(cwd: &Path, library: &Library, sources: &[String]) -> Result<Vec<PathBuf>, M8FstoErr> {
+    let mut expanded = vec![];
+
+    for source in sources {
+        if !is_glob_pattern(source) {
+            let path = in_backup(cwd, source);
+            if !path.exists() {
+                return Err(M8FstoErr::InvalidPath { reason: format!("Folder {:?} doesn't exists", path) })
+            }
+            expanded.push(path);
+            continue;
+        }
+
+        let pattern = if Path::new(source).is_relative() {
+            format!("{}/{}", glob::Pattern::escape(&cwd.to_string_lossy()), source)
+        } else {
+            source.clone()
+        };
+
+        let before = expanded.len();
+        for entry in glob(&pattern)
+            .map_err(|e| M8FstoErr::InvalidSearchPattern { pattern: format!("{:?}", e) })?
+            .flatten()
+            .filter(|entry| !library.is_excluded(entry)) {
+            expanded.push(normalize_path(&entry));
+        }
+
+        if expanded.len() == before {
+            return Err(M8FstoErr::InvalidPath { reason: format!("nothing matches {:?}", source) })
+        }
+    }
+
+    let mut seen = HashSet::new();
+    expanded.retain(|path| seen.insert(path.clone()));
+    Ok(expanded)
+}
+
+/// Samples used by the songs matching the query
+fn samples_used_by(cwd: &Path, query: &Option<String>, excludes: &[String]) -> Result<HashSet<PathBuf>, M8FstoErr> {
+    let mut used = HashSet::new();
+
+    Library::new(cwd)
+        .songs_only()
+        .exclude(excludes)?
+        .select(query)?
+        .walk(|file| {
+            for (_, instr) in file.instruments() {
+                match instr {
+                    Instrument::Sampler(sampler) if !sampler.sample_path.is_empty() => {
+                        used.insert(normalize_path(&sample_to_absolute_path(cwd, &file.path, &sampler.sample_path)));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        })?;
+
+    Ok(used)
+}
+
+/// Check the moves before touching anything: destinations must be free
+/// and distinct, and no source can be moved along with another one.
+fn check_moves(moves: &[(PathBuf, PathBuf)]) -> Option<M8FstoErr> {
+    let mut errors = None;
+    let mut destinations = HashMap::new();
+
+    for (from, to) in moves {
+        if to.exists() {
+            errors = combine(errors, M8FstoErr::InvalidPath {
+                reason: format!("cannot move {:?}, {:?} already exists", from, to)
+            });
+        }
+
+        if let Some(other) = destinations.insert(to.clone(), from.clone()) {
+            errors = combine(errors, M8FstoErr::InvalidPath {
+                reason: format!("{:?} and {:?} would both be moved to {:?}", other, from, to)
+            });
+        }
+
+        if to.starts_with(from) {
+            errors = combine(errors, M8FstoErr::InvalidPath {
+                reason: format!("cannot move {:?} inside itself", from)
+            });
+        }
+
+        if let Some((parent, _)) = moves.iter().find(|(other, _)| other != from && from.starts_with(other)) {
+            errors = combine(errors, M8FstoErr::InvalidPath {
+                reason: format!("{:?} is already moved with {:?}", from, parent)
+            });
+        }
+    }
+
+    errors
+}
+
+/// Check the sample paths the moved samples will have: they must be
+/// usable by the M8 and not collide with the files of the destination
+/// folders once on the SD card.
+fn check_destination_paths(cwd: &Path, moves: &[(PathBuf, PathBuf)], force: bool) -> Option<M8FstoErr> {
+    let mut errors = None;
+    let mut fat_names = FatNames::default();
+
+    let parents : HashSet<&Path> = moves.iter()
+        .filter_map(|(_, to)| to.parent())
+        .collect();
+
+    for parent in parents {
+        let Ok(entries) = fs::read_dir(parent) else { continue };
+        for entry in entries.flatten() {
+            if let Ok(existing) = to_sample_path(cwd, &entry.path()) {
+                fat_names.insert(&existing);
+            }
+        }
+    }
+
+    for (from, to) in moves {
+        let Ok(destination) = to_sample_path(cwd, to) else { continue };
+
+        if let Some(other) = fat_names.insert(&destination) {
+            let issue = PathIssue::Collision { other };
+            if force {
+                eprintln!("Warning: sample path \"{}\" : {}", destination, issue);
+            } else {
+                errors = combine(errors, issue_error(&destination, &issue));
+            }
+        }
+
+        let mut moved = vec![destination.clone()];
+        if from.is_dir() {
+            let pattern = format!("{}/**/*", glob::Pattern::escape(&from.to_string_lossy()));
+            for entry in glob(&pattern).into_iter().flatten().flatten() {
+                if let (true, Ok(rel)) = (is_sample_file(&entry), entry.strip_prefix(from)) {
+                    moved.push(format!("{}/{}", destination, rel.to_string_lossy().replace('\\', "/")));
+                }
+            }
+        }
+
+        for sample_path in moved {
+            if let Err(e) = check_sample_path(&sample_path, force) {
+                errors = combine(errors, e);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Move samples or sample folders and rewrite the songs and instruments
+/// using them in a single scan. Sources can be glob patterns, with several
+/// sources (or a destination folder that exists) each source is moved
+/// inside the destination folder. With a query, only the sources used
+/// by the matching songs are moved, a folder being moved whole when one
+/// of its samples is used, but every song is updated.
 pub fn move_samples(
     cwd: &Path,
     flags: FlagBag,
-    from: String,
-    to: String) -> Result<(), M8FstoErr> {
+    sources: Vec<String>,
+    to: String,
+    query: &Option<String>,
+    excludes: &[String]) -> Result<(), M8FstoErr> {
 
     let cwd = normalize_path(cwd);
+    let library = Library::new(&cwd).exclude(excludes)?;
 
     if flags.verbose {
         println!("Using backup at location: {:?}", cwd);
     }
 
-    let from_path = PathBuf::from(from);
-    if !from_path.exists() {
-        return Err(M8FstoErr::InvalidPath { reason: format!("Folder {:?} doesn't exists", from_path) })
+    let into_folder =
+        sources.len() > 1 || sources.iter().any(|s| is_glob_pattern(s));
+
+    let mut sources = expand_sources(&cwd, &library, &sources)?;
+    if query.is_some() {
+        let used = samples_used_by(&cwd, query, excludes)?;
+        sources.retain(|source| used.iter().any(|sample| sample.starts_with(source)));
+
+        if sources.is_empty() {
+            return Err(M8FstoErr::InvalidPath { reason: String::from("no source is used by the songs matching the query") })
+        }
     }
 
-    let to_path = PathBuf::from(to);
-    let to_canon =
-        if to_path.is_relative() {
-            let mut abs = cwd.clone();
-            abs.push(to_path);
-            abs
-        } else {
-            to_path
-        };
+    let to_canon = in_backup(&cwd, &to);
+    let into_folder = into_folder || to_canon.is_dir();
 
-    let from_canon = normalize_path(&from_path);
-    
-    if flags.verbose {
-        println!(" * moving source {:?}", from_canon);
+    if into_folder && !to_canon.is_dir() {
+        return Err(M8FstoErr::InvalidPath { reason: format!("Destination folder {:?} doesn't exists", to_canon) })
     }
 
-    let rel_from =
-        from_canon.strip_prefix(&cwd)
-            .map_err(| _| M8FstoErr::InvalidPath { reason: "from relativisation error".into() })?
-            .to_str()
-            .unwrap()
-            .replace("\\","/");
+    let moves : Vec<(PathBuf, PathBuf)> = sources
+        .into_iter()
+        .map(|from| {
+            let to = match (into_folder, from.file_name()) {
+                (true, Some(name)) => to_canon.join(name),
+                _ => to_canon.clone()
+            };
+            (from, to)
+        })
+        .collect();
+
+    if let Some(errs) = check_moves(&moves) {
+        return Err(errs)
+    }
 
-    if flags.verbose {
-        println!(" * to {:?}", to_canon);
+    if let Some(errs) = check_destination_paths(&cwd, &moves, flags.force) {
+        return Err(errs)
     }
 
-    let rel_to =
-        to_canon.strip_prefix(&cwd)
-            .map_err(| _| M8FstoErr::InvalidPath { reason: "destination canonicalization error".into() })?
-            .to_str()
-            .unwrap()
-            .replace("\\","/");
-
-    let move_order =
-        if from_path.is_dir() {
-            Swap::Dir {
-                from: format!("/{}", rel_from),
-                to: format!("/{}", rel_to),
-            }
-        } else if from_path.is_file() {
-            Swap::File {
-                from: format!("/{}", rel_from),
-                to: format!("/{}", rel_to),
-            }
+    let mut swaps = vec![];
+    for (from, to) in moves.iter() {
+        if flags.verbose {
+            println!(" * moving source {:?} to {:?}", from, to);
+        }
+
+        let rel_from = to_sample_path(&cwd, from)?;
+        let rel_to = to_sample_path(&cwd, to)?;
+
+        if from.is_dir() {
+            swaps.push(Swap::Dir { from: format!("{}/", rel_from), to: format!("{}/", rel_to) });
+        } else if from.is_file() {
+            swaps.push(Swap::File { from: rel_from, to: rel_to });
         } else {
-            return Err(M8FstoErr::CannotReadFile { path: from_path, reason: String::from("Neither file nor directory")})
-        };
+            return Err(M8FstoErr::CannotReadFile { path: from.clone(), reason: String::from("Neither file nor directory")})
+        }
+    }
+
+    let staged = stage(&flags, &cwd, &library, &swaps);
+    let description = format!("mv {} {:?}",
+        moves.iter().map(|(from, _)| format!("{:?}", from)).collect::<Vec<_>>().join(" "),
+        to_canon);
+
+    apply_staged(&flags, &cwd, description, staged, moves)
+}
+
+/// Errors found while staging, including the files that cannot be rewritten
+pub(crate) fn staged_errors(staged: &Staged) -> Option<M8FstoErr> {
+    let mut errors = staged.errors.clone();
+    for destination in staged.not_serializable.iter() {
+        errors = combine(errors, M8FstoErr::SongSerializationError {
+            destination: destination.clone(),
+            reason: String::from("Only version 4.0 or above song can be rewritten")
+        });
+    }
+    errors
+}
 
-    match on_dir(&flags, &cwd, &move_order) {
-        Ok(()) => {
-            std::fs::rename(&from_canon, to_canon)
-                .map_err(|_| M8FstoErr::RenameFailure { path: format!("{:?}", from_canon) })
+/// Write the staged files and rename the samples in a single transaction.
+/// Nothing is touched if some files cannot be rewritten, unless forced.
+pub(crate) fn apply_staged(
+    flags: &FlagBag,
+    cwd: &Path,
+    description: String,
+    staged: Staged,
+    renames: Vec<(PathBuf, PathBuf)>) -> Result<(), M8FstoErr> {
+
+    let errors = staged_errors(&staged);
+    if let Some(errs) = errors.as_ref() {
+        if !flags.force {
+            return Err(errs.clone())
         }
-        Err(errs) if flags.force => {
-            match std::fs::rename(&from_canon, to_canon) {
-                Ok(()) => Err(errs),
-                Err(_) => {
-                    let path = format!("{:?}", from_canon);
-                    Err(errs.combine(M8FstoErr::RenameFailure { path }))
-                }
-            }
+    }
+
+    if flags.dry_run {
+        return errors.map_or(Ok(()), Err)
+    }
+
+    let mut transaction = Transaction::new(cwd, description);
+    for (path, data) in staged.to_write {
+        transaction.write(path, data);
+    }
+    for (from, to) in renames {
+        transaction.rename(from, to);
+    }
+
+    match (transaction.commit(), errors) {
+        (Ok(()), None) => Ok(()),
+        (Ok(()), Some(errs)) => Err(errs),
+        (Err(e), None) => Err(e),
+        (Err(e), Some(errs)) => Err(errs.combine(e))
+    }
+}
+
+/// Make a sample path prefix start with a '/', like in songs
+fn as_sample_prefix(prefix: &str) -> String {
+    let prefix = prefix.replace('\\', "/");
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+/// Rewrite the sample paths starting with `old_prefix` to use `new_prefix`
+/// in all songs and instruments, without moving any file. The samples
+/// must already be at their new location.
+pub fn relink(cwd: &Path, flags: FlagBag, old_prefix: &str, new_prefix: &str, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let cwd = normalize_path(cwd);
+    let library = Library::new(&cwd).exclude(excludes)?;
+    let old_prefix = as_sample_prefix(old_prefix);
+    let new_prefix = as_sample_prefix(new_prefix);
+
+    let new_target = cwd.join(new_prefix.trim_start_matches('/'));
+    let swap = if new_target.is_file() {
+        Swap::File { from: old_prefix.clone(), to: new_prefix.clone() }
+    } else {
+        Swap::Dir { from: format!("{}/", old_prefix), to: format!("{}/", new_prefix) }
+    };
+
+    let mut staged = stage(&flags, &cwd, &library, &[swap]);
+
+    let mut missing : Vec<_> = staged.new_sample_paths
+        .iter()
+        .filter(|p| !cwd.join(p.trim_start_matches('/')).exists())
+        .cloned()
+        .collect();
+    missing.sort();
+    missing.dedup();
+
+    let mut new_paths = staged.new_sample_paths.clone();
+    new_paths.sort();
+    new_paths.dedup();
+    for sample_path in new_paths {
+        if let Err(e) = check_sample_path(&sample_path, flags.force) {
+            staged.errors = combine(staged.errors, e);
         }
-        Err(errs) => Err(errs)
     }
+
+    for sample_path in missing {
+        staged.errors = combine(staged.errors, M8FstoErr::InvalidPath {
+            reason: format!("new sample {:?} doesn't exist", sample_path)
+        });
+    }
+
+    let description = format!("relink {:?} {:?}", old_prefix, new_prefix);
+    apply_staged(&flags, &cwd, description, staged, vec![])
 }
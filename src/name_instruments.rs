@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use m8_file_parser::Instrument;
+
+use crate::{
+    journal::Transaction,
+    library::{Library, M8File},
+    sampler_gen::INSTRUMENT_NAME_LENGTH,
+    types::{FlagBag, M8FstoErr}
+};
+
+/// Symbols, beside upper case letters and digits, usable in names
+const NAME_SYMBOLS : &str = " -_.";
+
+/// Make a character displayable by the device
+fn sanitize_char(c: char) -> char {
+    let c = c.to_ascii_uppercase();
+    if c.is_ascii_uppercase() || c.is_ascii_digit() || NAME_SYMBOLS.contains(c) {
+        c
+    } else {
+        '_'
+    }
+}
+
+/// Instrument name derived from a file name, using only characters
+/// displayed by the device and truncated to the device limit.
+fn sampler_name(stem: &str) -> String {
+    let name : String = stem.trim()
+        .chars()
+        .map(sanitize_char)
+        .take(INSTRUMENT_NAME_LENGTH)
+        .collect();
+
+    name.trim_end().to_string()
+}
+
+/// Name a sampler from its sample file name, returning the old and
+/// new name if it changed.
+fn name_sampler(instr: &mut Instrument, overwrite: bool) -> Option<(String, String)> {
+    let Instrument::Sampler(sampler) = instr else { return None };
+
+    if sampler.sample_path.is_empty() || (!sampler.name.is_empty() && !overwrite) {
+        return None
+    }
+
+    let stem = Path::new(&sampler.sample_path)
+        .file_stem()?
+        .to_string_lossy()
+        .to_string();
+
+    let name = sampler_name(&stem);
+    if name == sampler.name {
+        return None
+    }
+
+    let old = std::mem::replace(&mut sampler.name, name.clone());
+    Some((old, name))
+}
+
+fn print_renames(path: &Path, renames: &[(usize, String, String)]) {
+    if renames.is_empty() {
+        return;
+    }
+
+    println!("\n{}", path.display());
+    for (ix, old, new) in renames {
+        println!("  {:02X} : \"{}\" -> \"{}\"", ix, old, new);
+    }
+}
+
+/// New content of the file if some samplers were renamed
+fn on_file(root: &Path, overwrite: bool, mut file: M8File) -> Result<Option<(PathBuf, Vec<u8>)>, M8FstoErr> {
+    let renames : Vec<_> = file.instruments_mut()
+        .into_iter()
+        .filter_map(|(ix, instr)| name_sampler(instr, overwrite).map(|(o, n)| (ix, o, n)))
+        .collect();
+
+    print_renames(file.relative_path(root), &renames);
+    if renames.is_empty() {
+        return Ok(None)
+    }
+
+    Ok(Some((file.path.clone(), file.serialize()?)))
+}
+
+/// Name the sampler instruments from their sample file names, in the songs
+/// and instruments files matching the path (file, folder or glob pattern).
+/// The files are rewritten together, the renaming can be undone.
+pub fn name_instruments(cwd: &Path, flags: FlagBag, overwrite: bool, path: &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let mut to_write = vec![];
+    let walked = Library::new(cwd)
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            if let Some(written) = on_file(cwd, overwrite, file)? {
+                to_write.push(written);
+            }
+            Ok(())
+        });
+
+    if flags.dry_run || to_write.is_empty() {
+        return walked
+    }
+
+    let description = format!("name-instruments {}", path.as_deref().unwrap_or(""));
+    let mut transaction = Transaction::new(cwd, description.trim_end().to_string());
+    for (path, data) in to_write {
+        transaction.write(path, data);
+    }
+
+    match (transaction.commit(), walked) {
+        (Ok(()), walked) => walked,
+        (Err(e), Ok(())) => Err(e),
+        (Err(e), Err(errs)) => Err(errs.combine(e))
+    }
+}
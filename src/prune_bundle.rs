@@ -16,18 +16,15 @@ fn on_file_blob(flags : FlagBag, song_path: &Path, data: Vec<u8>) -> Result<(),
     // First pass we gather all relative sample path, raise an error if one
     // is absolute.
     for (i, instr) in song.instruments.iter().enumerate() {
-        match instr {
-            Instrument::Sampler(sampler) => {
-                let sample_path = String::from(&sampler.sample_path);
-                if is_sample_absolute(&sample_path) {
-                    return Err(M8FstoErr::SampleInBundleNotRelative {
-                        sample_path, instrument: i
-                    });
-                }
-
-                all_samples.insert(sample_path);
+        if let Instrument::Sampler(sampler) = instr {
+            let sample_path = String::from(&sampler.sample_path);
+            if is_sample_absolute(&sample_path) {
+                return Err(M8FstoErr::SampleInBundleNotRelative {
+                    sample_path, instrument: i
+                });
             }
-            _ => {}
+
+            all_samples.insert(sample_path);
         }
     }
 
@@ -52,7 +49,7 @@ fn on_file_blob(flags : FlagBag, song_path: &Path, data: Vec<u8>) -> Result<(),
         }
     }
 
-    if to_remove.len() == 0 {
+    if to_remove.is_empty() {
         println!("Sample folder is clean, nothing to do!");
         return Ok(())
     }
@@ -65,7 +62,7 @@ fn on_file_blob(flags : FlagBag, song_path: &Path, data: Vec<u8>) -> Result<(),
     } else {
         for pb in &to_remove {
             println!("Removing '{:?}'", pb);
-            fs::remove_file(&pb)
+            fs::remove_file(pb)
                 .map_err(|e|
                     M8FstoErr::FileRemovalFailure { path: pb.clone(), reason: format!("{:?}", e) })?;
         }
@@ -0,0 +1,451 @@
+use std::{cell::OnceCell, path::Path};
+use glob::{MatchOptions, Pattern};
+use m8_file_parser::{Instrument, Song, Version};
+
+use crate::{
+    metadata::{compare_versions, instrument_kind, parse_version, Comparison, SongStats, INSTRUMENT_KINDS},
+    types::M8FstoErr
+};
+
+/// Fields usable in a query
+pub const QUERY_FIELDS : [&str; 9] =
+    ["name", "path", "version", "tempo", "chains", "phrases", "instruments", "samples", "sample"];
+
+/// Glob patterns of `~` are matched ignoring the case, like the
+/// FAT file system of the SD card.
+const MATCH_OPTIONS : MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false
+};
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Compare(Comparison),
+
+    /// Glob pattern match, `~`
+    Matches,
+
+    /// Glob pattern mismatch, `!~`
+    NotMatches
+}
+
+const OPERATORS : [(&str, Operator); 9] = [
+    ("<=", Operator::Compare(Comparison::LowerOrEqual)),
+    (">=", Operator::Compare(Comparison::GreaterOrEqual)),
+    ("!=", Operator::Compare(Comparison::Different)),
+    ("==", Operator::Compare(Comparison::Equal)),
+    ("!~", Operator::NotMatches),
+    ("<", Operator::Compare(Comparison::Lower)),
+    (">", Operator::Compare(Comparison::Greater)),
+    ("=", Operator::Compare(Comparison::Equal)),
+    ("~", Operator::Matches)
+];
+
+enum Token {
+    Open,
+    Close,
+    Op(Operator),
+
+    /// Bare word: keyword, field, function or unquoted value
+    Word(String),
+
+    /// Quoted value
+    Text(String)
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()'\"<>=!~".contains(c)
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = query.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if c == '\'' || c == '"' {
+            let end = rest[1..].find(c)
+                .ok_or_else(|| format!("unterminated string {}", rest))?;
+            tokens.push(Token::Text(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else if let Some((op, operator)) = OPERATORS.iter().find(|(op, _)| rest.starts_with(op)) {
+            tokens.push(Token::Op(*operator));
+            rest = &rest[op.len()..];
+        } else if is_word_char(c) {
+            let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("unexpected '{}'", c))
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Value compared with a field, parsed according to the field
+enum Value {
+    Number(f64),
+    Version(Version),
+    Text(String),
+    Pattern(Pattern)
+}
+
+enum Field {
+    Name,
+    Path,
+    Version,
+    Tempo,
+    Chains,
+    Phrases,
+    Instruments,
+    Samples,
+    Sample
+}
+
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Condition { field: Field, operator: Operator, value: Value },
+
+    /// `uses_instrument(Kind)`, kind is one of `INSTRUMENT_KINDS`
+    UsesInstrument(&'static str)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect_close(&mut self) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err(String::from("missing ')'"))
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and_expr()?;
+        while self.peek_keyword("or") {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek_keyword("and") {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek_keyword("not") {
+            self.position += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)))
+        }
+
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.or_expr()?;
+                self.expect_close()?;
+                Ok(expr)
+            }
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                if matches!(self.tokens.get(self.position), Some(Token::Open)) {
+                    self.position += 1;
+                    self.function(&word)
+                } else {
+                    self.condition(&word)
+                }
+            }
+            _ => Err(String::from("expecting a condition"))
+        }
+    }
+
+    fn argument(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(w)) | Some(Token::Text(w)) => Ok(w.clone()),
+            _ => Err(String::from("missing function argument"))
+        }
+    }
+
+    fn function(&mut self, name: &str) -> Result<Expr, String> {
+        if !name.eq_ignore_ascii_case("uses_instrument") {
+            return Err(format!("unknown function {}, only uses_instrument is available", name))
+        }
+
+        let kind = self.argument()?;
+        self.expect_close()?;
+
+        INSTRUMENT_KINDS.iter()
+            .find(|k| k.eq_ignore_ascii_case(&kind))
+            .map(|k| Expr::UsesInstrument(k))
+            .ok_or_else(|| format!("unknown instrument type {}, expecting one of {}", kind, INSTRUMENT_KINDS.join(", ")))
+    }
+
+    fn condition(&mut self, name: &str) -> Result<Expr, String> {
+        let field = match name.to_ascii_lowercase().as_str() {
+            "name" => Field::Name,
+            "path" => Field::Path,
+            "version" => Field::Version,
+            "tempo" => Field::Tempo,
+            "chains" => Field::Chains,
+            "phrases" => Field::Phrases,
+            "instruments" => Field::Instruments,
+            "samples" => Field::Samples,
+            "sample" => Field::Sample,
+            _ => return Err(format!("unknown field {}, expecting one of {}", name, QUERY_FIELDS.join(", ")))
+        };
+
+        let operator = match self.next() {
+            Some(Token::Op(operator)) => *operator,
+            _ => return Err(format!("missing comparison after {}", name))
+        };
+
+        let text = match self.next() {
+            Some(Token::Word(w)) | Some(Token::Text(w)) => w.clone(),
+            _ => return Err(format!("missing value after {}", name))
+        };
+
+        let value = match (&field, operator) {
+            (Field::Name | Field::Path | Field::Sample, Operator::Matches | Operator::NotMatches) =>
+                Value::Pattern(Pattern::new(&text).map_err(|e| format!("invalid pattern {} : {}", text, e.msg))?),
+            (_, Operator::Matches | Operator::NotMatches) =>
+                return Err(format!("{} cannot be matched with a pattern", name)),
+            (Field::Name | Field::Path | Field::Sample, _) => Value::Text(text),
+            (Field::Version, _) => Value::Version(parse_version(&text)
+                .ok_or_else(|| format!("invalid version {}", text))?),
+            _ => Value::Number(text.parse()
+                .map_err(|_| format!("{} expects a number, not {}", name, text))?)
+        };
+
+        Ok(Expr::Condition { field, operator, value })
+    }
+}
+
+/// Song being tested, the figures are only computed when needed
+struct Subject<'a> {
+    path: &'a Path,
+    song: &'a Song,
+    stats: OnceCell<SongStats>
+}
+
+impl Subject<'_> {
+    fn stats(&self) -> &SongStats {
+        self.stats.get_or_init(|| SongStats::of_song(self.song))
+    }
+
+    fn number(&self, field: &Field) -> f64 {
+        match field {
+            Field::Tempo => self.song.tempo as f64,
+            Field::Chains => self.stats().used_chains as f64,
+            Field::Phrases => self.stats().used_phrases as f64,
+            Field::Instruments => self.stats().instruments.total() as f64,
+            _ => self.stats().distinct_samples as f64
+        }
+    }
+
+    /// Text values of the field, a song has many samples
+    fn texts(&self, field: &Field) -> Vec<String> {
+        match field {
+            Field::Name => vec![self.song.name.clone()],
+            Field::Path => vec![self.path.to_string_lossy().replace('\\', "/")],
+            _ => self.song.instruments.iter()
+                .filter_map(|i| match i {
+                    Instrument::Sampler(s) if !s.sample_path.is_empty() => Some(s.sample_path.clone()),
+                    _ => None
+                })
+                .collect()
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, subject: &Subject) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(subject) || b.eval(subject),
+            Expr::And(a, b) => a.eval(subject) && b.eval(subject),
+            Expr::Not(e) => !e.eval(subject),
+            Expr::UsesInstrument(kind) =>
+                subject.song.instruments.iter().any(|i| instrument_kind(i) == *kind),
+            Expr::Condition { field, operator, value } => match (operator, value) {
+                (Operator::NotMatches, _) =>
+                    !Expr::eval_condition(subject, field, Operator::Matches, value),
+                (Operator::Compare(Comparison::Different), Value::Text(_)) =>
+                    !Expr::eval_condition(subject, field, Operator::Compare(Comparison::Equal), value),
+                _ => Expr::eval_condition(subject, field, *operator, value)
+            }
+        }
+    }
+
+    /// A condition on the samples holds if any sample satisfies it
+    fn eval_condition(subject: &Subject, field: &Field, operator: Operator, value: &Value) -> bool {
+        match (operator, value) {
+            (Operator::Compare(c), Value::Number(n)) =>
+                c.holds(subject.number(field).total_cmp(n)),
+            (Operator::Compare(c), Value::Version(v)) =>
+                c.holds(compare_versions(subject.song.version, *v)),
+            (Operator::Compare(c), Value::Text(t)) =>
+                subject.texts(field).iter().any(|s| c.holds(s.as_str().cmp(t.as_str()))),
+            (_, Value::Pattern(p)) =>
+                subject.texts(field).iter().any(|s| p.matches_with(s, MATCH_OPTIONS)),
+            _ => false
+        }
+    }
+}
+
+/// Selection of songs on their metadata, like
+/// `tempo > 140 and uses_instrument(HyperSynth) and sample ~ '*/909/*'`
+pub struct Query {
+    expr: Expr
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Query, M8FstoErr> {
+        let invalid = |reason| M8FstoErr::InvalidQuery { query: query.to_string(), reason };
+
+        let mut parser = Parser { tokens: tokenize(query).map_err(invalid)?, position: 0 };
+        let expr = parser.or_expr().map_err(invalid)?;
+
+        match parser.tokens.get(parser.position) {
+            None => Ok(Query { expr }),
+            Some(_) => Err(invalid(String::from("unexpected text after the condition")))
+        }
+    }
+
+    /// Does the song match, `path` is relative to the backup root
+    pub fn matches(&self, path: &Path, song: &Song) -> bool {
+        self.expr.eval(&Subject { path, song, stats: OnceCell::new() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use m8_file_parser::{reader::Reader, Instrument, Song};
+
+    use crate::{metadata::parse_version, sampler_gen::new_sampler, types::M8FstoErr};
+    use super::Query;
+
+    /// Empty song with the given name, tempo and samplers
+    fn song(name: &str, tempo: f32, samples: &[&str]) -> Song {
+        let data = include_bytes!("../testdata/EMPTY.m8s").to_vec();
+        let mut song = Song::read_from_reader(&mut Reader::new(data)).unwrap();
+        song.name = name.to_string();
+        song.tempo = tempo;
+        for (i, sample) in samples.iter().enumerate() {
+            song.instruments[i] = Instrument::Sampler(new_sampler("S", sample));
+        }
+        song
+    }
+
+    fn matches(query: &str, song: &Song) -> bool {
+        match Query::parse(query) {
+            Ok(q) => q.matches(Path::new("Songs/SONG.m8s"), song),
+            Err(e) => panic!("{} : {}", query, e)
+        }
+    }
+
+    fn error(query: &str) -> String {
+        match Query::parse(query) {
+            Err(M8FstoErr::InvalidQuery { reason, .. }) => reason,
+            Err(e) => panic!("{} : unexpected error {}", query, e),
+            Ok(_) => panic!("{} : should not parse", query)
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let s = song("A", 120.0, &[]);
+        assert!(matches("name = A or name = B and tempo > 200", &s));
+        assert!(!matches("(name = A or name = B) and tempo > 200", &s));
+        assert!(matches("tempo > 200 and name = B or name = A", &s));
+    }
+
+    #[test]
+    fn not_applies_to_the_next_condition() {
+        let a = song("A", 120.0, &[]);
+        let b = song("B", 120.0, &[]);
+        assert!(!matches("not name = A and tempo > 100", &a));
+        assert!(matches("not name = A and tempo > 100", &b));
+        assert!(!matches("not (name = B or tempo > 100)", &a));
+        assert!(matches("not not name = A", &a));
+        assert!(matches("NOT name = B AND tempo = 120", &a));
+    }
+
+    #[test]
+    fn quoted_strings() {
+        let s = song("MY SONG", 120.0, &[]);
+        assert!(matches("name = 'MY SONG'", &s));
+        assert!(matches("name = \"MY SONG\"", &s));
+        assert!(matches("name ~ 'my *'", &s));
+        assert!(!matches("name = 'MY SONG and tempo > 10'", &s));
+
+        let s = song("a<b", 120.0, &[]);
+        assert!(matches("name = 'a<b'", &s));
+    }
+
+    #[test]
+    fn sample_conditions_hold_for_any_sample() {
+        let s = song("A", 120.0, &["/Samples/909/kick.wav", "/Samples/808/snare.wav"]);
+        assert!(matches("sample ~ '*/909/*'", &s));
+        assert!(!matches("sample !~ '*/909/*'", &s));
+        assert!(matches("sample !~ '*/707/*'", &s));
+        assert!(matches("sample = '/Samples/808/snare.wav'", &s));
+        assert!(!matches("sample != '/Samples/808/snare.wav'", &s));
+        assert!(matches("sample != '/Samples/707/snare.wav'", &s));
+        assert!(matches("samples = 2", &s));
+
+        let empty = song("A", 120.0, &[]);
+        assert!(!matches("sample ~ '*'", &empty));
+        assert!(matches("sample !~ '*'", &empty));
+        assert!(matches("sample != x", &empty));
+    }
+
+    #[test]
+    fn versions_compare_by_component() {
+        let mut s = song("A", 120.0, &[]);
+        s.version = parse_version("4.1.2").unwrap();
+        assert!(matches("version = 4.1.2", &s));
+        assert!(matches("version > 4.1", &s));
+        assert!(matches("version < 4.10", &s));
+        assert!(matches("version >= 4.1.2 and version <= 4.1.2", &s));
+        assert!(!matches("version != 4.1.2", &s));
+        assert!(!matches("version < 4", &s));
+    }
+
+    #[test]
+    fn invalid_queries() {
+        assert!(error("name = 'A").starts_with("unterminated string"));
+        assert!(error("title = A").starts_with("unknown field title"));
+        assert!(error("uses_sample(kick)").starts_with("unknown function uses_sample"));
+        assert_eq!(error("name = A tempo > 100"), "unexpected text after the condition");
+        assert_eq!(error("(name = A"), "missing ')'");
+        assert_eq!(error("tempo >"), "missing value after tempo");
+        assert_eq!(error("tempo ~ 12*"), "tempo cannot be matched with a pattern");
+        assert!(error("version = 4.x").starts_with("invalid version"));
+        assert!(error("uses_instrument(Piano)").starts_with("unknown instrument type Piano"));
+    }
+}
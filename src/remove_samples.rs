@@ -0,0 +1,196 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use m8_file_parser::Instrument;
+
+use crate::{
+    broken_search::sample_to_absolute_path,
+    journal::{Transaction, JOURNAL_FOLDER, TRASH_FOLDER},
+    library::{Library, M8File},
+    m8_path::check_sample_path,
+    move_samples::{in_backup, normalize_path, to_sample_path},
+    types::{FlagBag, M8FstoErr}
+};
+
+/// Instruments using the removed samples, and what happen to them
+struct Unlinker<'a> {
+    backup_root: &'a Path,
+
+    /// Removed file or folder
+    target: &'a Path,
+
+    /// Sample path given to the instruments using a removed sample,
+    /// None to clear them.
+    replacement: &'a Option<String>,
+
+    /// How many instruments use the removed samples
+    references: usize
+}
+
+impl Unlinker<'_> {
+    /// Report a sampler using a removed sample and repoint it,
+    /// returning true if the instrument changed.
+    fn unlink(&mut self, path: &Path, ix: usize, instr: &mut Instrument) -> bool {
+        let Instrument::Sampler(sampler) = instr else { return false };
+        if sampler.sample_path.is_empty() {
+            return false
+        }
+
+        let full_path =
+            normalize_path(&sample_to_absolute_path(self.backup_root, path, &sampler.sample_path));
+
+        if !full_path.starts_with(self.target) {
+            return false
+        }
+
+        self.references += 1;
+        println!("{}:{:02X} {} : {}",
+            path.strip_prefix(self.backup_root).unwrap_or(path).display(),
+            ix,
+            sampler.name,
+            sampler.sample_path);
+
+        sampler.sample_path = self.replacement.clone().unwrap_or_default();
+        true
+    }
+
+    /// New content of a song or instrument file using the removed
+    /// samples, None if the file doesn't use them.
+    fn on_file(&mut self, mut file: M8File) -> Result<Option<Vec<u8>>, M8FstoErr> {
+        let path = file.path.clone();
+        let mut changed = false;
+        for (ix, instr) in file.instruments_mut() {
+            changed |= self.unlink(&path, ix, instr);
+        }
+
+        if !changed {
+            return Ok(None)
+        }
+
+        file.serialize().map(Some)
+    }
+}
+
+/// Sample path of the replacement sample, which must exist
+/// and not be removed.
+fn replacement_path(backup_root: &Path, target: &Path, replacement: &str, force: bool) -> Result<String, M8FstoErr> {
+    let replacement = in_backup(backup_root, replacement);
+    if !replacement.is_file() {
+        return Err(M8FstoErr::InvalidPath { reason: format!("replacement {:?} is not a sample", replacement) })
+    }
+
+    if replacement.starts_with(target) {
+        return Err(M8FstoErr::InvalidPath { reason: format!("replacement {:?} is removed", replacement) })
+    }
+
+    let sample_path = to_sample_path(backup_root, &replacement)?;
+    check_sample_path(&sample_path, force)?;
+    Ok(sample_path)
+}
+
+/// Remove a sample or a sample folder, refusing if a song or an instrument
+/// still uses it, unless forced: the instruments are then cleared. With a
+/// replacement, they use the replacement sample instead. Songs that cannot
+/// be read may use the samples, they stop the removal unless ignored.
+/// The removal can be undone, removed files are kept in the trash.
+pub fn remove_samples(
+    backup_root: &Path,
+    flags: FlagBag,
+    target: &str,
+    replacement: &Option<String>,
+    ignore_unreadable: bool,
+    excludes: &[String]) -> Result<(), M8FstoErr> {
+
+    let backup_root = normalize_path(backup_root);
+    let target = in_backup(&backup_root, target);
+
+    if !target.exists() {
+        return Err(M8FstoErr::InvalidPath { reason: format!("{:?} doesn't exists", target) })
+    }
+
+    if target == backup_root
+        || !target.starts_with(&backup_root)
+        || target.starts_with(backup_root.join(JOURNAL_FOLDER))
+        || target.starts_with(backup_root.join(TRASH_FOLDER)) {
+        return Err(M8FstoErr::InvalidPath { reason: format!("cannot remove {:?}", target) })
+    }
+
+    let replacement = match replacement {
+        None => None,
+        Some(r) => Some(replacement_path(&backup_root, &target, r, flags.force)?)
+    };
+
+    let mut unlinker = Unlinker {
+        backup_root: &backup_root,
+        target: &target,
+        replacement: &replacement,
+        references: 0
+    };
+
+    let mut to_write : Vec<(PathBuf, Vec<u8>)> = vec![];
+    let library = Library::new(&backup_root).exclude(excludes)?;
+    let walked = library.walk(|file| {
+        // removed along with the samples
+        if file.path.starts_with(&target) {
+            return Ok(())
+        }
+
+        let path = file.path.clone();
+        if let Some(data) = unlinker.on_file(file)? {
+            to_write.push((path, data));
+        }
+        Ok(())
+    });
+    let errors = walked.err();
+
+    if unlinker.references > 0 && replacement.is_none() && !flags.force {
+        return Err(M8FstoErr::InvalidPath {
+            reason: format!("{:?} is used by {} instruments, use --force to clear them or --replace to repoint them",
+                target,
+                unlinker.references)
+        })
+    }
+
+    if let Some(errs) = errors.as_ref() {
+        if !ignore_unreadable {
+            return Err(errs.clone()
+                .combine(M8FstoErr::InvalidPath {
+                    reason: String::from("unreadable files may use the samples, use --ignore-unreadable to remove them anyway")
+                }))
+        }
+    }
+
+    println!(" * remove {:?}", target);
+    if flags.dry_run {
+        return errors.map_or(Ok(()), Err)
+    }
+
+    let mut transaction = Transaction::new(&backup_root, format!("rm {:?}", target));
+    for (path, data) in to_write {
+        transaction.write(path, data);
+    }
+    transaction.remove(target);
+
+    match (transaction.commit(), errors) {
+        (Ok(()), None) => Ok(()),
+        (Ok(()), Some(errs)) => Err(errs),
+        (Err(e), None) => Err(e),
+        (Err(e), Some(errs)) => Err(errs.combine(e))
+    }
+}
+
+/// Delete for good the files removed by `rm`
+pub fn empty_trash(backup_root: &Path, flags: &FlagBag) -> Result<(), M8FstoErr> {
+    let trash = backup_root.join(TRASH_FOLDER);
+    if !trash.exists() {
+        println!("No trash folder, nothing to do!");
+        return Ok(())
+    }
+
+    println!("Removing {:?}", trash);
+    if flags.dry_run {
+        return Ok(())
+    }
+
+    fs::remove_dir_all(&trash)
+        .map_err(|e| M8FstoErr::FileRemovalFailure { path: trash, reason: format!("{:?}", e) })
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, fmt::Display, fs, path::{Path, PathBuf}};
+use m8_file_parser::{Instrument, Song};
+
+use crate::{
+    aiff,
+    broken_search::sample_to_absolute_path,
+    bundle::human_size,
+    library::{Library, M8Content},
+    types::M8FstoErr,
+    wav::{WavFile, M8_SAMPLE_RATE}
+};
+
+/// Default amount of sample memory of the device, in megabytes
+pub const DEFAULT_MEMORY_LIMIT_MB : f64 = 64.0;
+
+/// How many samples are listed as the biggest offenders
+const OFFENDER_COUNT : usize = 5;
+
+/// Bytes used on the device by one sample of one channel
+const DEVICE_BYTES_PER_SAMPLE : u64 = 2;
+
+/// Convert a limit expressed in megabytes to bytes
+pub fn limit_of_megabytes(mb: f64) -> u64 {
+    (mb * 1024.0 * 1024.0) as u64
+}
+
+/// Memory used by a sample once loaded on the device
+pub struct SampleFootprint {
+    /// Sample path as written in the song
+    pub m8_path: String,
+
+    /// Decoded size in bytes
+    pub bytes: u64
+}
+
+/// Memory used by all the distinct samples of a song
+pub struct SongMemory {
+    /// Footprint of every distinct sample, biggest first
+    pub samples: Vec<SampleFootprint>,
+
+    /// Samples that cannot be found or read, not counted
+    /// in the total.
+    pub missing: Vec<String>,
+
+    /// Sum of the footprints
+    pub total: u64
+}
+
+/// Already computed sample footprints, to avoid reading the same sample
+/// file again when checking many songs.
+pub type FootprintCache = HashMap<PathBuf, Option<u64>>;
+
+/// Compute the memory taken by a sample once loaded on the device,
+/// samples are stored as 16 bits and resampled to 44.1kHz.
+fn sample_footprint(full_path: &Path) -> Option<u64> {
+    let bytes = fs::read(full_path).ok()?;
+
+    let (channels, sample_rate, frames) = if aiff::is_aiff(&bytes) {
+        let audio = aiff::decode(&bytes).ok()?;
+        (audio.channels, audio.sample_rate, audio.frame_count())
+    } else {
+        let wav = WavFile::parse(&bytes).ok()?;
+        (wav.channels, wav.sample_rate, wav.frame_count())
+    };
+
+    if sample_rate == 0 {
+        return None
+    }
+
+    let device_frames =
+        (frames as u64 * M8_SAMPLE_RATE as u64).div_ceil(sample_rate as u64);
+
+    Some(device_frames * channels as u64 * DEVICE_BYTES_PER_SAMPLE)
+}
+
+impl SongMemory {
+    /// Compute the footprint of every distinct sample used by the song
+    pub fn of_song(cache: &mut FootprintCache, backup_root: &Path, song_path: &Path, song: &Song) -> SongMemory {
+        let mut seen = HashMap::new();
+
+        for instr in song.instruments.iter() {
+            if let Instrument::Sampler(sampler) = instr {
+                if sampler.sample_path.is_empty() {
+                    continue;
+                }
+
+                let full_path =
+                    sample_to_absolute_path(backup_root, song_path, &sampler.sample_path);
+
+                seen.entry(full_path)
+                    .or_insert_with(|| sampler.sample_path.clone());
+            }
+        }
+
+        let mut memory = SongMemory { samples: vec![], missing: vec![], total: 0 };
+
+        for (full_path, m8_path) in seen {
+            let footprint = *cache
+                .entry(full_path.clone())
+                .or_insert_with(|| sample_footprint(&full_path));
+
+            match footprint {
+                None => memory.missing.push(m8_path),
+                Some(bytes) => {
+                    memory.total += bytes;
+                    memory.samples.push(SampleFootprint { m8_path, bytes });
+                }
+            }
+        }
+
+        memory.samples.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.m8_path.cmp(&b.m8_path)));
+        memory.missing.sort();
+        memory
+    }
+
+    /// Can the song samples be loaded with the given memory
+    pub fn fits(&self, limit: u64) -> bool {
+        self.total <= limit
+    }
+}
+
+/// Display the memory budget of a song, with the biggest samples
+pub struct MemoryReport<'a> {
+    pub memory: &'a SongMemory,
+    pub limit: u64
+}
+
+impl<'a> Display for MemoryReport<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let memory = self.memory;
+        let percent = (memory.total * 100).checked_div(self.limit).unwrap_or(0);
+
+        write!(f, "Sample memory        : {} / {} ({}%)",
+            human_size(memory.total),
+            human_size(self.limit),
+            percent)?;
+
+        if !memory.fits(self.limit) {
+            write!(f, " TOO BIG, the song will not load")?;
+        }
+
+        for sample in memory.samples.iter().take(OFFENDER_COUNT) {
+            write!(f, "\n  {:>10} {}", human_size(sample.bytes), sample.m8_path)?;
+        }
+
+        if !memory.missing.is_empty() {
+            write!(f, "\n  missing samples    : {} (not counted)", memory.missing.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the sample memory of every song matching the pattern, and list
+/// the ones that would not load on the device.
+pub fn check_memory(backup_root: &Path, pattern: &Option<String>, limit: u64, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let mut cache = FootprintCache::new();
+    let mut checked = 0;
+    let mut too_big = 0;
+
+    let walked = Library::new(backup_root)
+        .exclude(excludes)?
+        .songs_only()
+        .include(pattern.as_slice())
+        .walk(|file| {
+            let M8Content::Song(song) = &file.content else { return Ok(()) };
+
+            checked += 1;
+            let memory = SongMemory::of_song(&mut cache, backup_root, &file.path, song);
+            if memory.fits(limit) {
+                return Ok(())
+            }
+
+            too_big += 1;
+            println!("\n{} ({})", file.relative_path(backup_root).display(), song.name);
+            println!("{}", MemoryReport { memory: &memory, limit });
+            Ok(())
+        });
+
+    println!("\n{} songs over the {} sample memory limit ({} songs checked)",
+        too_big, human_size(limit), checked);
+
+    walked
+}
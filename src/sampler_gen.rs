@@ -0,0 +1,169 @@
+use std::{collections::HashSet, path::{Path, PathBuf}};
+
+use m8_file_parser::{reader::Reader, writer::Writer};
+use m8_file_parser::{
+    AHDEnv, Instrument, InstrumentWithEq, LfoShape, LfoTriggerMode, LimitType, Mod,
+    SamplePlayMode, Sampler, Song, SynthParams, Table, Version, LFO
+};
+
+use crate::{move_samples::normalize_path, types::M8FstoErr};
+
+/// Maximum length of an instrument name on the device
+pub const INSTRUMENT_NAME_LENGTH : usize = 12;
+
+/// Version used to write generated instruments
+const INSTRUMENT_VERSION : Version = Version { major: 4, minor: 2, patch: 0 };
+
+/// Instrument name derived from a file name, truncated to
+/// the device limit.
+pub fn instrument_name(stem: &str) -> String {
+    stem.chars().take(INSTRUMENT_NAME_LENGTH).collect()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    normalize_path(&std::path::absolute(path).unwrap_or(path.to_path_buf()))
+}
+
+/// Sample path as seen by the device, absolute from the backup root.
+pub fn device_sample_path(backup_root: &Path, sample: &Path) -> Result<String, M8FstoErr> {
+    let backup_root = absolute(backup_root);
+    let sample = absolute(sample);
+    let relative = sample.strip_prefix(&backup_root)
+        .map_err(|_| M8FstoErr::InvalidPath {
+            reason: format!("{:?} is not in the backup root {:?}", sample, backup_root)
+        })?;
+
+    let components : Vec<_> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+
+    Ok(format!("/{}", components.join("/")))
+}
+
+/// Default synth parameters of the generated instruments
+pub fn default_synth_params() -> SynthParams {
+    let ahd = AHDEnv {
+        dest: 0,
+        amount: 0xFF,
+        attack: 0,
+        hold: 0,
+        decay: 0x80,
+    };
+
+    let lfo = LFO {
+        shape: LfoShape::TRI,
+        dest: 0,
+        trigger_mode: LfoTriggerMode::FREE,
+        freq: 0x10,
+        amount: 0xFF,
+        retrigger: 0,
+    };
+
+    SynthParams {
+        volume: 0x0,
+        pitch: 0,
+        fine_tune: 0x80,
+        filter_type: 0,
+        filter_cutoff: 0xFF,
+        filter_res: 0x0,
+        amp: 0,
+        limit: LimitType(0),
+        mixer_pan: 0x80,
+        mixer_dry: 0xC0,
+        mixer_mfx: 0,
+        mixer_delay: 0,
+        mixer_reverb: 0x00,
+        associated_eq: 0x80,
+        mods: [
+            Mod::AHDEnv(ahd.clone()),
+            Mod::AHDEnv(ahd),
+            Mod::LFO(lfo.clone()),
+            Mod::LFO(lfo),
+        ],
+    }
+}
+
+/// Sampler playing the whole sample forward
+pub fn new_sampler(name: &str, sample_path: &str) -> Sampler {
+    Sampler {
+        number: 0,
+        name: instrument_name(name),
+        transpose: true,
+        table_tick: 1,
+        synth_params: default_synth_params(),
+        sample_path: sample_path.to_string(),
+        play_mode: SamplePlayMode::FWD,
+        slice: 0,
+        start: 0,
+        loop_start: 0,
+        length: 0xFF,
+        degrade: 0
+    }
+}
+
+/// Write an instrument as a `.m8i` file
+pub fn write_instrument(path: &Path, instrument: Instrument) -> Result<(), M8FstoErr> {
+    let instrument = InstrumentWithEq {
+        instrument,
+        table: Table::default_ver(INSTRUMENT_VERSION),
+        eq: None,
+        version: INSTRUMENT_VERSION,
+    };
+
+    let mut w = Writer::new_instrument_writer(false);
+    instrument.write(&mut w);
+
+    std::fs::write(path, w.finish())
+        .map_err(|err|
+            M8FstoErr::SongSerializationError {
+                destination: format!("{:?}", path),
+                reason: format!("{}", err)
+            })
+}
+
+/// Name not already used, replacing the end of the name with
+/// a counter on conflicts.
+pub fn unique_name(used: &mut HashSet<String>, name: &str) -> String {
+    let mut candidate = instrument_name(name);
+    let mut counter = 1;
+
+    while used.contains(&candidate) {
+        let suffix = format!("~{}", counter);
+        let kept : String = name.chars().take(INSTRUMENT_NAME_LENGTH - suffix.len()).collect();
+        candidate = format!("{}{}", kept, suffix);
+        counter += 1;
+    }
+
+    used.insert(candidate.clone());
+    candidate
+}
+
+/// Load a song used as a base to write generated songs
+pub fn load_base_song(path: &Path) -> Result<(Vec<u8>, Song), M8FstoErr> {
+    let data = std::fs::read(path)
+        .map_err(|e| M8FstoErr::CannotReadFile { path: path.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    let song = Song::read_from_reader(&mut Reader::new(data.clone()))
+        .map_err(|e| M8FstoErr::UnparseableM8File { path: path.to_path_buf(), reason: format!("{:?}", e) })?;
+
+    Ok((data, song))
+}
+
+/// Write a song, using the bytes of the song it was loaded from.
+pub fn write_song(path: &Path, base_data: Vec<u8>, song: &Song) -> Result<(), M8FstoErr> {
+    let mut writer = Writer::new(base_data);
+    song.write(&mut writer)
+        .map_err(|reason|
+            M8FstoErr::SongSerializationError {
+                destination: format!("{:?}", path),
+                reason
+            })?;
+
+    std::fs::write(path, writer.finish())
+        .map_err(|reason|
+            M8FstoErr::SongSerializationError {
+                destination: format!("{:?}", path),
+                reason: format!("{:?}", reason)
+            })
+}
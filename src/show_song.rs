@@ -1,8 +1,15 @@
-use std::{collections::HashSet, fmt::Display, fs, path::PathBuf};
+use std::{fmt::Display, fs, path::{Path, PathBuf}};
 
 use m8_file_parser::{param_gatherer::{Describable, ParameterGatherer}, reader::Reader, Instrument, Version};
 
-use crate::{types::M8FstoErr, ShowCommand, ShowTarget};
+use crate::{
+    library::{Library, M8Content},
+    metadata::{instrument_kind, SongStats},
+    sample_memory::{limit_of_megabytes, FootprintCache, MemoryReport, SongMemory},
+    types::M8FstoErr,
+    ShowCommand,
+    ShowTarget
+};
 
 struct AsciiTherer<'a, 'writer> {
     write: &'a mut std::fmt::Formatter<'writer>,
@@ -101,17 +108,11 @@ impl<T : Describable> Display for ElemDisplay<T> {
     }
 }
 
-fn instrument_kind(i: &Instrument) -> &'static str {
-    match i {
-        Instrument::WavSynth(_) => "WavSynth",
-        Instrument::MacroSynth(_) => "MacroSynth",
-        Instrument::Sampler(_) => "Sample",
-        Instrument::MIDIOut(_) => "MIDIOut",
-        Instrument::FMSynth(_) => "FMSynth",
-        Instrument::HyperSynth(_) => "HyperSynth",
-        Instrument::External(_) => "External",
-        Instrument::None => "None"
-    }
+fn show_instrument_info(w: &mut dyn std::io::Write, instr_eq: &m8_file_parser::InstrumentWithEq) -> Result<(), M8FstoErr> {
+    writeln!(w, "Version : {}", instr_eq.version).map_err(|_| M8FstoErr::PrintError)?;
+    writeln!(w, "Name    : {}", instr_eq.instrument.name().unwrap_or("")).map_err(|_| M8FstoErr::PrintError)?;
+    writeln!(w, "Kind    : {}", instrument_kind(&instr_eq.instrument)).map_err(|_| M8FstoErr::PrintError)?;
+    Ok(())
 }
 
 fn show_from_instrument(show: ShowCommand, w: &mut dyn std::io::Write, instr_eq: m8_file_parser::InstrumentWithEq) -> Result<(), M8FstoErr> {
@@ -119,12 +120,7 @@ fn show_from_instrument(show: ShowCommand, w: &mut dyn std::io::Write, instr_eq:
         ShowTarget::Song => Ok(()),
         ShowTarget::Mixer => Ok(()),
         ShowTarget::Effects => Ok(()),
-        ShowTarget::Info => {
-            writeln!(w, "Version : {}", instr_eq.version).map_err(|_| M8FstoErr::PrintError)?;
-            writeln!(w, "Name    : {}", instr_eq.instrument.name().unwrap_or("")).map_err(|_| M8FstoErr::PrintError)?;
-            writeln!(w, "Kind    : {}", instrument_kind(&instr_eq.instrument)).map_err(|_| M8FstoErr::PrintError)?;
-            Ok(())
-        },
+        ShowTarget::Info { .. } => show_instrument_info(w, &instr_eq),
         ShowTarget::Chain { id: _ } => Ok(()),
         ShowTarget::Phrase { id: _} => Ok(()),
         ShowTarget::Instrument { id: _ } => {
@@ -152,129 +148,71 @@ fn show_from_instrument(show: ShowCommand, w: &mut dyn std::io::Write, instr_eq:
 
 /// Structure used to instantiate Display instance for song info
 struct SongInfoDisplay<'a> {
-    song: &'a m8_file_parser::Song
-}
-
-struct InstrumentCounter {
-    pub wavsynth_count : usize,
-    pub macrosynth_count : usize,
-    pub fm_count : usize,
-    pub sampler_count : usize,
-    pub midi_count : usize,
-    pub external_count : usize,
-    pub hypersynth_count: usize,
-
-    pub used_midi_channel : HashSet<u8>
-}
-
-impl InstrumentCounter {
-    pub fn total(&self) -> usize {
-        self.wavsynth_count +
-            self.macrosynth_count +
-            self.fm_count +
-            self.sampler_count +
-            self.midi_count +
-            self.external_count +
-            self.hypersynth_count
-    }
-
-    pub fn count(mut self, instr: &m8_file_parser::Instrument) -> Self {
-        match instr {
-            Instrument::None => (),
-            Instrument::WavSynth(_) => self.wavsynth_count += 1,
-            Instrument::MacroSynth(_) => self.macrosynth_count += 1,
-            Instrument::Sampler(_) => self.sampler_count += 1,
-            Instrument::MIDIOut(midiout) => {
-                self.midi_count += 1;
-                self.used_midi_channel.insert(midiout.channel);
-            },
-            Instrument::FMSynth(_) => self.fm_count += 1,
-            Instrument::HyperSynth(_) => self.hypersynth_count += 1,
-            Instrument::External(ext) => {
-                self.external_count += 1;
-                self.used_midi_channel.insert(ext.channel);
-            },
-        };
-
-        self
-    }
-}
-
-impl Default for InstrumentCounter {
-    fn default() -> Self {
-        Self {
-            wavsynth_count: Default::default(),
-            macrosynth_count: Default::default(),
-            sampler_count: Default::default(),
-            midi_count: Default::default(),
-            fm_count: Default::default(),
-            external_count: Default::default(),
-            hypersynth_count: Default::default(),
-            used_midi_channel: Default::default()
-        }
-    }
-}
+    song: &'a m8_file_parser::Song,
 
-impl Display for InstrumentCounter {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Instruments count    : {}", self.total())?;
-        writeln!(f, "            Wavsynth : {}", self.wavsynth_count)?;
-        writeln!(f, "          Macrosynth : {}", self.macrosynth_count)?;
-        writeln!(f, "             Sampler : {}", self.sampler_count)?;
-        writeln!(f, "             FmSynth : {}", self.fm_count)?;
-        writeln!(f, "          HyperSynth : {}", self.hypersynth_count)?;
-        writeln!(f, "            MIDI out : {}", self.midi_count)?;
-        writeln!(f, "           Ext instr : {}", self.external_count)?;
-
-        let midi_vec : Vec<_> = self.used_midi_channel.iter().map(|c| format!("{}", c)).collect();
-        write!(f, "  used midi channels : {}", midi_vec.join(", "))?;
-        Ok(())
-    }
+    /// Sample memory used by the song, and device limit
+    memory: MemoryReport<'a>
 }
 
 impl<'a> Display for SongInfoDisplay<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = self.song;
-
-
-        let samples : HashSet<_> = s.instruments
-            .iter()
-            .filter_map(|i|
-                match i {
-                    Instrument::Sampler(s) => Some(s.sample_path.clone()),
-                    _ => None
-                })
-            .collect();
+        let stats = SongStats::of_song(s);
 
         writeln!(f, "Version              : {}", s.version)?;
         writeln!(f, "Name                 : {}", s.name)?;
+        writeln!(f, "{}", stats.instruments)?;
 
-        let instr_count = s.instruments.iter()
-            .fold(
-                InstrumentCounter::default(),
-                |acc, i| acc.count(i));
+        writeln!(f, "Distinct samples     : {}", stats.distinct_samples)?;
+        writeln!(f, "{}", self.memory)?;
+        writeln!(f, "Non flat EQs         : {}", stats.non_flat_eqs)?;
+        writeln!(f, "Non empty table      : {}", stats.non_empty_tables)?;
+        writeln!(f, "Used chains          : {}", stats.used_chains)?;
+        writeln!(f, "Used phrases         : {}", stats.used_phrases)?;
 
-        writeln!(f, "{}", instr_count)?;
-
-        writeln!(f, "Distinct samples     : {}", samples.len())?;
-
-        let eqs = s.eqs.iter().filter(|t| !t.is_empty()).count();
-        writeln!(f, "Non flat EQs         : {}", eqs)?;
-
-        let tables = s.tables.iter().filter(|t| !t.is_empty()).count();
-        writeln!(f, "Non empty table      : {}", tables)?;
+        Ok(())
+    }
+}
 
-        let chains = s.chains.iter().filter(|c| !c.is_empty()).count();
-        writeln!(f, "Used chains          : {}", chains)?;
+/// Root of the sample paths, the current directory by default
+fn info_root(cwd: &Path, root: &Option<String>) -> PathBuf {
+    root.as_ref().map_or_else(|| cwd.to_path_buf(), PathBuf::from)
+}
 
-        let phrases = s.phrases.iter().filter(|c| !c.is_empty()).count();
-        writeln!(f, "Used phrases         : {}", phrases)?;
+fn show_song_info(
+    w: &mut dyn std::io::Write,
+    cache: &mut FootprintCache,
+    root: &Path,
+    song_path: &Path,
+    song: &m8_file_parser::Song,
+    limit: f64) -> Result<(), M8FstoErr> {
+
+    let memory = SongMemory::of_song(cache, root, song_path, song);
+    let info = SongInfoDisplay {
+        song,
+        memory: MemoryReport { memory: &memory, limit: limit_of_megabytes(limit) }
+    };
+    writeln!(w, "{}", info).map_err(|_| M8FstoErr::PrintError)
+}
 
-        Ok(())
-    }
+/// Display the information of every song and instrument in a folder
+/// or matching a glob pattern, the files are parsed in parallel.
+fn show_many_infos(w: &mut dyn std::io::Write, cwd: &Path, pattern: &str, root: &Option<String>, limit: f64) -> Result<(), M8FstoErr> {
+    let root = info_root(cwd, root);
+    let mut cache = FootprintCache::new();
+
+    Library::new(&root)
+        .include(&[pattern.to_string()])
+        .walk(|file| {
+            writeln!(w, "\n{}", file.path.display()).map_err(|_| M8FstoErr::PrintError)?;
+            match &file.content {
+                M8Content::Song(song) => show_song_info(w, &mut cache, &root, &file.path, song, limit),
+                M8Content::Instrument(instr_eq) => show_instrument_info(w, instr_eq)
+            }
+        })
 }
 
-fn show_from_song(show: ShowCommand, w: &mut dyn std::io::Write, song: m8_file_parser::Song) -> Result<(), M8FstoErr> {
+fn show_from_song(show: ShowCommand, w: &mut dyn std::io::Write, cwd: &Path, song_path: &Path, song: m8_file_parser::Song) -> Result<(), M8FstoErr> {
     match show.show_command {
         ShowTarget::Song => {
             writeln!(w, "{}", song.song).map_err(|_| M8FstoErr::PrintError)
@@ -291,8 +229,8 @@ fn show_from_song(show: ShowCommand, w: &mut dyn std::io::Write, song: m8_file_p
                 ver: song.version
             }).map_err(|_| M8FstoErr::PrintError)
         }
-        ShowTarget::Info => {
-            writeln!(w, "{}", SongInfoDisplay { song: &song }).map_err(|_| M8FstoErr::PrintError)
+        ShowTarget::Info { root, limit } => {
+            show_song_info(w, &mut FootprintCache::new(), &info_root(cwd, &root), song_path, &song, limit)
         }
         ShowTarget::Chain { id } => {
             writeln!(w, "{}", song.chains[id]).map_err(|_| M8FstoErr::PrintError)
@@ -353,8 +291,16 @@ fn show_from_song(show: ShowCommand, w: &mut dyn std::io::Write, song: m8_file_p
     }
 }
 
-pub fn show_element(show: ShowCommand, w: &mut dyn std::io::Write) -> Result<(), M8FstoErr> {
+/// Display an element of a song or instrument file, sample paths
+/// are searched from `cwd` unless a root is given.
+pub fn show_element(show: ShowCommand, cwd: &Path, w: &mut dyn std::io::Write) -> Result<(), M8FstoErr> {
     let song_path = PathBuf::from(show.file.clone());
+    if let ShowTarget::Info { root, limit } = &show.show_command {
+        if !song_path.is_file() {
+            return show_many_infos(w, cwd, &show.file, root, *limit)
+        }
+    }
+
     let file_blob = fs::read(song_path.clone())
         .map_err(|e|
             M8FstoErr::CannotReadFile { path: song_path.clone(), reason: format!("{:?}", e) })?;
@@ -362,7 +308,7 @@ pub fn show_element(show: ShowCommand, w: &mut dyn std::io::Write) -> Result<(),
     let mut reader = Reader::new(file_blob);
 
     match m8_file_parser::Song::read_from_reader(&mut reader) {
-        Ok(song) => show_from_song(show, w, song),
+        Ok(song) => show_from_song(show, w, cwd, &song_path, song),
         Err(e) => {
             reader.set_pos(0);
             match m8_file_parser::Instrument::read_from_reader(&mut reader) {
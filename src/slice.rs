@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use m8_file_parser::Instrument;
+
+use crate::{
+    bundle::human_size,
+    sampler_gen::{device_sample_path, new_sampler, write_instrument},
+    types::M8FstoErr,
+    wav::{Audio, WavFile}
+};
+
+/// Maximum number of slices used by the sampler
+pub const MAX_SLICES : usize = 128;
+
+/// Number of frames between two energy measures
+const ONSET_HOP : usize = 256;
+
+/// Number of frames over which the energy is measured
+const ONSET_WINDOW : usize = 1024;
+
+/// Minimum time between two detected transients, in seconds
+const ONSET_MIN_GAP : f64 = 0.05;
+
+/// Below this level relative to the loudest part, rises are ignored
+const ONSET_SILENCE_DB : f64 = -50.0;
+
+/// How to cut the sample
+pub enum SliceMode {
+    /// Cut when the energy rises by more than the given dB
+    Transients { threshold_db: f64 },
+
+    /// Cut in N slices of the same length
+    Equal { count: usize }
+}
+
+/// Slice start positions, in frames, for slices of the same length
+fn equal_slices(frame_count: usize, count: usize) -> Vec<u32> {
+    (0 .. count)
+        .map(|i| (i * frame_count / count) as u32)
+        .collect()
+}
+
+/// Energy in dB of successive windows of the mono signal
+fn energy_envelope(mono: &[f32]) -> Vec<f64> {
+    (0 .. mono.len().div_ceil(ONSET_HOP))
+        .map(|i| {
+            let start = i * ONSET_HOP;
+            let window = &mono[start .. (start + ONSET_WINDOW).min(mono.len())];
+            let energy : f64 = window.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+            10.0 * (energy / ONSET_WINDOW as f64 + 1e-12).log10()
+        })
+        .collect()
+}
+
+/// Slice start positions, in frames, at every detected transient.
+/// The first slice always starts at the beginning of the sample.
+fn transient_slices(audio: &Audio, threshold_db: f64) -> Vec<u32> {
+    let mono = audio.to_mono();
+    let envelope = energy_envelope(&mono.samples);
+
+    let loudest = envelope.iter().cloned().fold(f64::MIN, f64::max);
+    let min_gap = (ONSET_MIN_GAP * audio.sample_rate as f64) as usize;
+
+    let mut slices = vec![0];
+    for i in 1 .. envelope.len() {
+        let rise = envelope[i] - envelope[i - 1];
+        let is_peak = rise >= threshold_db
+            && envelope[i] - loudest > ONSET_SILENCE_DB
+            && (i + 1 >= envelope.len() || rise >= envelope[i + 1] - envelope[i]);
+
+        if !is_peak {
+            continue;
+        }
+
+        // the window ending at the current hop holds the attack
+        let position = (i * ONSET_HOP + ONSET_WINDOW).saturating_sub(ONSET_HOP);
+        let position = position.min(mono.frame_count());
+        let last = *slices.last().unwrap() as usize;
+
+        if position >= last + min_gap && slices.len() < MAX_SLICES {
+            slices.push(position as u32);
+        }
+    }
+
+    slices
+}
+
+/// Default output, next to the input sample
+fn default_output(input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    input.with_file_name(format!("{}_sliced.wav", stem))
+}
+
+/// Write a copy of a WAV sample with cue markers at every slice,
+/// optionally writing a sampler instrument using the slices.
+pub fn slice_sample(
+    backup_root: &Path,
+    input: &Path,
+    output: &Option<String>,
+    mode: SliceMode,
+    instrument: bool) -> Result<(), M8FstoErr> {
+
+    let mut wav = WavFile::read(input)?;
+    let frame_count = wav.frame_count();
+    if frame_count == 0 {
+        return Err(M8FstoErr::UnparseableSample {
+            path: input.to_path_buf(),
+            reason: String::from("empty sample")
+        })
+    }
+
+    let slices = match mode {
+        SliceMode::Equal { count } => equal_slices(frame_count, count),
+        SliceMode::Transients { threshold_db } => {
+            let audio = wav.decode()
+                .map_err(|reason| M8FstoErr::UnparseableSample { path: input.to_path_buf(), reason })?;
+            transient_slices(&audio, threshold_db)
+        }
+    };
+
+    let output = output.as_ref()
+        .map_or_else(|| default_output(input), PathBuf::from);
+
+    for (i, position) in slices.iter().enumerate() {
+        println!("  {:02X} : {:.3} s", i, *position as f64 / wav.sample_rate as f64);
+    }
+
+    wav.cues = slices;
+    let size = wav.write(&output)?;
+    println!("Wrote {} slices in {:?} ({})", wav.cues.len(), output, human_size(size));
+
+    if instrument {
+        let sample_path = device_sample_path(backup_root, &output)?;
+        let name = output.file_stem().unwrap_or_default().to_string_lossy();
+
+        let mut sampler = new_sampler(&name, &sample_path);
+        sampler.slice = wav.cues.len() as u8;
+
+        let instrument_path = output.with_extension("m8i");
+        write_instrument(&instrument_path, Instrument::Sampler(sampler))?;
+        println!("Wrote sampler instrument {:?} playing {}", instrument_path, sample_path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{equal_slices, slice_sample, transient_slices, SliceMode, MAX_SLICES};
+    use crate::wav::{Audio, WavFile};
+
+    /// One second of silence with short bursts starting at the given frames
+    fn bursts(starts: &[usize]) -> Audio {
+        let mut samples = vec![0.0; 44100];
+        for start in starts {
+            for (i, sample) in samples[*start .. *start + 2000].iter_mut().enumerate() {
+                *sample = if i % 2 == 0 { 0.5 } else { -0.5 };
+            }
+        }
+        Audio { channels: 1, sample_rate: 44100, samples, cues: vec![] }
+    }
+
+    #[test]
+    fn equal_slices_cover_the_sample() {
+        assert_eq!(equal_slices(1000, 4), vec![0, 250, 500, 750]);
+        assert_eq!(equal_slices(10, 3), vec![0, 3, 6]);
+        assert_eq!(equal_slices(1000, MAX_SLICES).len(), MAX_SLICES);
+    }
+
+    #[test]
+    fn a_slice_at_every_burst() {
+        let starts = [5000, 15000, 30000];
+        let slices = transient_slices(&bursts(&starts), 6.0);
+
+        assert_eq!(slices.len(), starts.len() + 1);
+        assert_eq!(slices[0], 0);
+        for (slice, start) in slices[1..].iter().zip(starts) {
+            assert!((*slice as usize).abs_diff(start) < 1024, "slice {} for a burst at {}", slice, start);
+        }
+    }
+
+    #[test]
+    fn silence_has_a_single_slice() {
+        assert_eq!(transient_slices(&bursts(&[]), 6.0), vec![0]);
+    }
+
+    #[test]
+    fn sliced_copy_holds_the_cues() {
+        let folder = std::env::temp_dir().join(format!("m8fsto-slice-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let input = folder.join("LOOP.wav");
+        bursts(&[]).to_pcm_wav(16).write(&input).unwrap();
+        slice_sample(&folder, &input, &None, SliceMode::Equal { count: 8 }, false).unwrap();
+
+        let sliced = WavFile::read(&folder.join("LOOP_sliced.wav")).unwrap();
+        assert_eq!(sliced.cues, equal_slices(44100, 8));
+        assert_eq!(sliced.frame_count(), 44100);
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use m8_file_parser::{Instrument, Note};
+
+use crate::{
+    broken_search::sample_to_absolute_path,
+    convert_samples::decode_sample,
+    journal::Transaction,
+    library::{Library, M8File},
+    types::{FlagBag, M8FstoErr},
+    wav::Audio
+};
+
+/// Frames used to compare the signal with its delayed copy
+const YIN_WINDOW : usize = 2048;
+
+/// Biggest period searched, in frames
+const YIN_MAX_PERIOD : usize = 2048;
+
+/// Highest frequency searched, in Hz
+const YIN_MAX_FREQUENCY : f64 = 2000.0;
+
+/// YIN absolute threshold, first dip below this value is taken
+const YIN_THRESHOLD : f64 = 0.15;
+
+/// Skip the attack of the sample, in seconds
+const ANALYSIS_OFFSET : f64 = 0.05;
+
+/// How many windows are analyzed in the sample
+const ANALYSIS_WINDOWS : usize = 5;
+
+/// Windows quieter than this RMS level are ignored
+const SILENCE_RMS : f64 = 1e-3;
+
+/// MIDI number of the note played at the original speed by the
+/// sampler, C-4 on the device.
+const SAMPLER_ROOT_MIDI : f64 = 60.0;
+
+/// MIDI number of the first note of the device (C-1)
+const DEVICE_FIRST_MIDI : f64 = 24.0;
+
+/// Fine tune value playing the sample without detune
+const FINE_TUNE_CENTER : f64 = 128.0;
+
+/// Fine tune steps in a semitone
+const FINE_TUNE_STEPS : f64 = 128.0;
+
+/// Fundamental found in a sample
+#[derive(Clone, Copy)]
+struct Pitch {
+    /// Fractional MIDI note number
+    midi: f64,
+
+    /// Between 0 and 1, how periodic is the signal
+    confidence: f64
+}
+
+impl Pitch {
+    /// Closest device note and the distance to it in cents
+    fn note(&self) -> (Note, i32) {
+        let rounded = self.midi.round();
+        let index = (rounded - DEVICE_FIRST_MIDI).clamp(0.0, 127.0) as u8;
+        (Note(index), ((self.midi - rounded) * 100.0).round() as i32)
+    }
+
+    /// `pitch` and `fine_tune` values to play the sample in tune
+    /// from C-4.
+    fn tuning(&self) -> (u8, u8) {
+        let shift = SAMPLER_ROOT_MIDI - self.midi;
+        let semitones = shift.round();
+        let fine = FINE_TUNE_CENTER + (shift - semitones) * FINE_TUNE_STEPS;
+
+        ((semitones as i8) as u8, fine.round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+/// Run YIN on one window of the signal, returning the period in frames
+/// and the confidence.
+fn yin(signal: &[f32], min_period: usize) -> Option<(f64, f64)> {
+    let mut difference = vec![0.0f64; YIN_MAX_PERIOD + 1];
+    for (tau, d) in difference.iter_mut().enumerate().skip(1) {
+        *d = (0 .. YIN_WINDOW)
+            .map(|j| {
+                let delta = (signal[j] - signal[j + tau]) as f64;
+                delta * delta
+            })
+            .sum();
+    }
+
+    // cumulative mean normalized difference
+    let mut normalized = vec![1.0f64; YIN_MAX_PERIOD + 1];
+    let mut running = 0.0;
+    for tau in 1 ..= YIN_MAX_PERIOD {
+        running += difference[tau];
+        normalized[tau] = if running > 0.0 { difference[tau] * tau as f64 / running } else { 1.0 };
+    }
+
+    let first_dip = (min_period .. YIN_MAX_PERIOD)
+        .find(|&tau| normalized[tau] < YIN_THRESHOLD);
+
+    let mut tau = match first_dip {
+        Some(tau) => tau,
+        None => (min_period .. YIN_MAX_PERIOD)
+            .min_by(|a, b| normalized[*a].total_cmp(&normalized[*b]))?
+    };
+
+    while tau + 1 < YIN_MAX_PERIOD && normalized[tau + 1] < normalized[tau] {
+        tau += 1;
+    }
+
+    // parabolic interpolation around the minimum
+    let (prev, cur, next) = (normalized[tau - 1], normalized[tau], normalized[tau + 1]);
+    let denominator = prev + next - 2.0 * cur;
+    let offset = if denominator.abs() > 1e-12 { (prev - next) / (2.0 * denominator) } else { 0.0 };
+
+    Some((tau as f64 + offset.clamp(-1.0, 1.0), (1.0 - cur).clamp(0.0, 1.0)))
+}
+
+/// Find the fundamental of a sample, keeping the most confident
+/// of a few windows after the attack.
+fn detect_pitch(audio: &Audio) -> Option<Pitch> {
+    let mono = audio.to_mono();
+    let sample_rate = audio.sample_rate as f64;
+    let needed = YIN_WINDOW + YIN_MAX_PERIOD + 1;
+    let min_period = ((sample_rate / YIN_MAX_FREQUENCY) as usize).max(2);
+
+    let first = ((ANALYSIS_OFFSET * sample_rate) as usize)
+        .min(mono.samples.len().saturating_sub(needed));
+
+    let mut best : Option<Pitch> = None;
+    for i in 0 .. ANALYSIS_WINDOWS {
+        let start = first + i * YIN_WINDOW;
+        if start + needed > mono.samples.len() {
+            break;
+        }
+
+        let window = &mono.samples[start .. start + needed];
+        let rms = (window.iter().map(|s| (*s as f64) * (*s as f64)).sum::<f64>() / needed as f64).sqrt();
+        if rms < SILENCE_RMS {
+            continue;
+        }
+
+        let Some((period, confidence)) = yin(window, min_period) else { continue };
+        let frequency = sample_rate / period;
+        let pitch = Pitch {
+            midi: 69.0 + 12.0 * (frequency / 440.0).log2(),
+            confidence
+        };
+
+        if best.is_none_or(|b| pitch.confidence > b.confidence) {
+            best = Some(pitch);
+        }
+    }
+
+    best
+}
+
+/// Pitches found for the samples, to analyze each sample once
+type PitchCache = HashMap<PathBuf, Option<Pitch>>;
+
+fn sample_pitch(cache: &mut PitchCache, full_path: &Path) -> Option<Pitch> {
+    *cache.entry(full_path.to_path_buf())
+        .or_insert_with(|| {
+            let bytes = fs::read(full_path).ok()?;
+            let audio = decode_sample(&bytes).ok()?;
+            detect_pitch(&audio)
+        })
+}
+
+/// How the instruments must be tuned
+pub struct TuneOptions {
+    /// Write the tuning in the files
+    pub apply: bool,
+
+    /// Pitches found with less confidence are not applied
+    pub min_confidence: f64
+}
+
+/// Detect the pitch of a sampler, report it and tune the instrument
+/// if asked, returning true if the instrument changed.
+fn tune_instrument(
+    cache: &mut PitchCache,
+    options: &TuneOptions,
+    backup_root: &Path,
+    path: &Path,
+    ix: usize,
+    instr: &mut Instrument) -> bool {
+
+    let Instrument::Sampler(sampler) = instr else { return false };
+    if sampler.sample_path.is_empty() {
+        return false
+    }
+
+    let full_path = sample_to_absolute_path(backup_root, path, &sampler.sample_path);
+    let Some(pitch) = sample_pitch(cache, &full_path) else {
+        println!("  {:02X} {:12} : no pitch found", ix, sampler.name);
+        return false
+    };
+
+    let (note, cents) = pitch.note();
+    let (semitones, fine) = pitch.tuning();
+    let confident = pitch.confidence >= options.min_confidence;
+
+    println!("  {:02X} {:12} : {} {:+} cents (confidence {:.2}) -> PITCH {:02X} FINE {:02X}{}",
+        ix,
+        sampler.name,
+        note,
+        cents,
+        pitch.confidence,
+        semitones,
+        fine,
+        if confident { "" } else { " (not confident)" });
+
+    if !options.apply || !confident {
+        return false
+    }
+
+    let changed = sampler.synth_params.pitch != semitones || sampler.synth_params.fine_tune != fine;
+    sampler.synth_params.pitch = semitones;
+    sampler.synth_params.fine_tune = fine;
+    changed
+}
+
+/// New content of the file if some instruments were tuned
+fn on_file(
+    cache: &mut PitchCache,
+    options: &TuneOptions,
+    backup_root: &Path,
+    mut file: M8File) -> Result<Option<(PathBuf, Vec<u8>)>, M8FstoErr> {
+
+    if !file.instruments().iter().any(|(_, i)| matches!(i, Instrument::Sampler(_))) {
+        return Ok(None)
+    }
+
+    println!("\n{}", file.relative_path(backup_root).display());
+    let path = file.path.clone();
+    let mut changed = false;
+    for (ix, instr) in file.instruments_mut() {
+        changed |= tune_instrument(cache, options, backup_root, &path, ix, instr);
+    }
+
+    if !changed {
+        return Ok(None)
+    }
+
+    Ok(Some((path, file.serialize()?)))
+}
+
+/// Detect the pitch of the samples used by the sampler instruments of
+/// the songs and instrument files matching the path, and optionally
+/// tune the instruments to play in tune from C-4. The tuned files are
+/// rewritten together, the tuning can be undone.
+pub fn tune_samples(backup_root: &Path, flags: FlagBag, options: TuneOptions, path: &Option<String>, excludes: &[String]) -> Result<(), M8FstoErr> {
+    let mut cache = PitchCache::new();
+    let mut to_write = vec![];
+
+    let walked = Library::new(backup_root)
+        .include(path.as_slice())
+        .exclude(excludes)?
+        .walk(|file| {
+            if let Some(written) = on_file(&mut cache, &options, backup_root, file)? {
+                to_write.push(written);
+            }
+            Ok(())
+        });
+
+    if flags.dry_run || to_write.is_empty() {
+        return walked
+    }
+
+    let description = format!("tune-samples --apply {}", path.as_deref().unwrap_or(""));
+    let mut transaction = Transaction::new(backup_root, description.trim_end().to_string());
+    for (path, data) in to_write {
+        transaction.write(path, data);
+    }
+
+    match (transaction.commit(), walked) {
+        (Ok(()), walked) => walked,
+        (Err(e), Ok(())) => Err(e),
+        (Err(e), Err(errs)) => Err(errs.combine(e))
+    }
+}
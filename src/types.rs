@@ -19,7 +19,10 @@ pub struct FlagBag {
 #[derive(Debug, Clone)]
 pub enum M8FstoErr {
     UnparseableM8File { path: PathBuf, reason: String },
+    UnparseableSample { path: PathBuf, reason: String },
     InvalidSearchPattern { pattern: String },
+    InvalidQuery { query: String, reason: String },
+    InvalidArgument { argument: String, reason: String },
     CannotReadFile { path: PathBuf, reason: String },
     SampleCopyError { path: PathBuf, to: PathBuf, reason: String },
     SongSerializationError { destination: String, reason: String },
@@ -33,6 +36,8 @@ pub enum M8FstoErr {
     FileRemovalFailure { path: PathBuf, reason: String },
     InvalidPath { reason: String },
     RenameFailure { path: String },
+    JournalError { reason: String },
+    UnusableSamplePath { path: String, reason: String },
     PrintError
 }
 
@@ -73,9 +78,18 @@ impl Display for M8FstoErr {
             M8FstoErr::UnparseableM8File { path, reason } => {
                 writeln!(f, "Can't parse M8 file '{:?}' : {}", path.as_path(), reason)
             }
+            M8FstoErr::UnparseableSample { path, reason } => {
+                writeln!(f, "Can't parse sample '{:?}' : {}", path.as_path(), reason)
+            }
             M8FstoErr::InvalidSearchPattern { pattern } => {
                 writeln!(f, "Invalid search pattern '{}'", pattern)
             },
+            M8FstoErr::InvalidQuery { query, reason } => {
+                writeln!(f, "Invalid query '{}' : {}", query, reason)
+            },
+            M8FstoErr::InvalidArgument { argument, reason } => {
+                writeln!(f, "Invalid argument '{}' : {}", argument, reason)
+            },
             M8FstoErr::CannotReadFile { path, reason } => {
                 writeln!(f, "Cannot read file '{:?}' : {}", path, reason)
             },
@@ -109,6 +123,12 @@ impl Display for M8FstoErr {
             M8FstoErr::RenameFailure { path } => {
                 writeln!(f, "Cannot rename file or folder \"{:?}\"", path)
             }
+            M8FstoErr::JournalError { reason } => {
+                writeln!(f, "Journal error: {}", reason)
+            }
+            M8FstoErr::UnusableSamplePath { path, reason } => {
+                writeln!(f, "Sample path \"{}\" cannot be used on the M8 : {}", path, reason)
+            }
         }
     }
 }
\ No newline at end of file
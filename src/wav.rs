@@ -0,0 +1,735 @@
+use std::{fmt::Display, fs, path::Path};
+
+use crate::types::M8FstoErr;
+
+/// Integer PCM samples
+pub const WAVE_FORMAT_PCM : u16 = 0x0001;
+
+/// 32 or 64 bits floating point samples
+pub const WAVE_FORMAT_IEEE_FLOAT : u16 = 0x0003;
+
+/// Format described by a sub format in the extended part
+/// of the fmt chunk.
+pub const WAVE_FORMAT_EXTENSIBLE : u16 = 0xFFFE;
+
+/// Native sample rate of the M8, other rates are resampled
+pub const M8_SAMPLE_RATE : u32 = 44100;
+
+/// Size of a cue point in the `cue ` chunk
+const CUE_POINT_SIZE : usize = 24;
+
+/// Size of the header of the `smpl` chunk, before the loops
+const SAMPLER_HEADER_SIZE : usize = 36;
+
+/// Size of a loop in the `smpl` chunk
+const SAMPLER_LOOP_SIZE : usize = 24;
+
+/// Problem found with a sample regarding M8 playback
+pub enum SampleIssue {
+    /// The M8 won't be able to load the sample
+    Unsupported(String),
+
+    /// The sample can be loaded, but may not behave as expected
+    Warning(String)
+}
+
+impl Display for SampleIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleIssue::Unsupported(reason) => write!(f, "UNSUPPORTED {}", reason),
+            SampleIssue::Warning(reason) => write!(f, "warning {}", reason),
+        }
+    }
+}
+
+/// Chunk of a WAV file we don't interpret, copied as is
+#[derive(Clone)]
+pub struct RawChunk {
+    pub id: [u8; 4],
+    pub content: Vec<u8>
+}
+
+/// In memory representation of a WAV file, fmt, data and cue
+/// points are decoded, the other chunks are kept untouched.
+#[derive(Clone)]
+pub struct WavFile {
+    /// Format tag as written in the file
+    pub format_tag: u16,
+
+    /// Channel count
+    pub channels: u16,
+
+    /// Frame per seconds
+    pub sample_rate: u32,
+
+    /// Bits used by one sample of one channel
+    pub bits_per_sample: u16,
+
+    /// Size in bytes of one frame (all channels)
+    pub block_align: u16,
+
+    /// Raw fmt chunk, kept to rewrite the file in the
+    /// same format.
+    fmt_chunk: Vec<u8>,
+
+    /// Raw audio data
+    pub data: Vec<u8>,
+
+    /// Cue points positions, in frames
+    pub cues: Vec<u32>,
+
+    /// Other chunks (loop points, metadata...), in file order
+    pub other_chunks: Vec<RawChunk>
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+impl WavFile {
+    /// Parse a whole WAV file loaded in memory
+    pub fn parse(bytes: &[u8]) -> Result<WavFile, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(String::from("not a RIFF/WAVE file"))
+        }
+
+        let mut fmt_chunk = None;
+        let mut data = None;
+        let mut cues = vec![];
+        let mut other_chunks = vec![];
+        let mut cursor = 12;
+
+        while cursor + 8 <= bytes.len() {
+            let chunk_id = &bytes[cursor .. cursor + 4];
+            let chunk_size = read_u32(bytes, cursor + 4) as usize;
+            let chunk_start = cursor + 8;
+            // truncated files are common, be lenient on the last chunk
+            let chunk_end = (chunk_start + chunk_size).min(bytes.len());
+            let chunk = &bytes[chunk_start .. chunk_end];
+
+            match chunk_id {
+                b"fmt " => fmt_chunk = Some(chunk.to_vec()),
+                b"data" => data = Some(chunk.to_vec()),
+                b"cue " if chunk.len() >= 4 => {
+                    let count = read_u32(chunk, 0) as usize;
+                    for i in 0 .. count {
+                        let point = 4 + i * CUE_POINT_SIZE;
+                        if point + CUE_POINT_SIZE > chunk.len() { break; }
+                        cues.push(read_u32(chunk, point + 20));
+                    }
+                }
+                b"cue " => {}
+                _ => other_chunks.push(RawChunk {
+                    id: [chunk_id[0], chunk_id[1], chunk_id[2], chunk_id[3]],
+                    content: chunk.to_vec()
+                })
+            }
+
+            // chunks are word aligned
+            cursor = chunk_start + chunk_size + (chunk_size & 1);
+        }
+
+        let fmt_chunk = fmt_chunk.ok_or(String::from("missing fmt chunk"))?;
+        let data = data.ok_or(String::from("missing data chunk"))?;
+
+        if fmt_chunk.len() < 16 {
+            return Err(String::from("fmt chunk too small"))
+        }
+
+        let block_align = read_u16(&fmt_chunk, 12);
+        if block_align == 0 {
+            return Err(String::from("invalid block alignment"))
+        }
+
+        cues.sort();
+
+        Ok(WavFile {
+            format_tag: read_u16(&fmt_chunk, 0),
+            channels: read_u16(&fmt_chunk, 2),
+            sample_rate: read_u32(&fmt_chunk, 4),
+            bits_per_sample: read_u16(&fmt_chunk, 14),
+            block_align,
+            fmt_chunk,
+            data,
+            cues,
+            other_chunks
+        })
+    }
+
+    /// Load and parse a WAV file from the disk
+    pub fn read(path: &Path) -> Result<WavFile, M8FstoErr> {
+        let bytes = fs::read(path)
+            .map_err(|e| M8FstoErr::CannotReadFile {
+                path: path.to_path_buf(),
+                reason: format!("{:?}", e)
+            })?;
+
+        WavFile::parse(&bytes)
+            .map_err(|reason| M8FstoErr::UnparseableSample { path: path.to_path_buf(), reason })
+    }
+
+    /// Format of the samples, resolving the sub format of
+    /// extensible files.
+    pub fn sample_format(&self) -> u16 {
+        if self.format_tag == WAVE_FORMAT_EXTENSIBLE && self.fmt_chunk.len() >= 26 {
+            read_u16(&self.fmt_chunk, 24)
+        } else {
+            self.format_tag
+        }
+    }
+
+    /// Human readable description of the sample encoding
+    pub fn format_name(&self) -> String {
+        match self.sample_format() {
+            WAVE_FORMAT_PCM => format!("{}-bit PCM", self.bits_per_sample),
+            WAVE_FORMAT_IEEE_FLOAT => format!("{}-bit float", self.bits_per_sample),
+            0x0002 => String::from("MS ADPCM"),
+            0x0006 => String::from("A-law"),
+            0x0007 => String::from("mu-law"),
+            0x0011 => String::from("IMA ADPCM"),
+            0x0055 => String::from("MP3"),
+            other => format!("format 0x{:04X}", other)
+        }
+    }
+
+    /// List the problems the M8 would have playing this file
+    pub fn m8_issues(&self) -> Vec<SampleIssue> {
+        let mut issues = vec![];
+
+        match (self.sample_format(), self.bits_per_sample) {
+            (WAVE_FORMAT_PCM, 8 | 16 | 24 | 32) => {}
+            (WAVE_FORMAT_IEEE_FLOAT, 32) =>
+                issues.push(SampleIssue::Warning(String::from("32-bit float, unsupported by older firmwares"))),
+            (WAVE_FORMAT_PCM | WAVE_FORMAT_IEEE_FLOAT, bits) =>
+                issues.push(SampleIssue::Unsupported(format!("unsupported bit depth {}", bits))),
+            _ =>
+                issues.push(SampleIssue::Unsupported(format!("compressed or unknown encoding ({})", self.format_name()))),
+        }
+
+        if self.channels == 0 || self.channels > 2 {
+            issues.push(SampleIssue::Unsupported(format!("{} channels", self.channels)));
+        }
+
+        if self.sample_rate != M8_SAMPLE_RATE {
+            issues.push(SampleIssue::Warning(format!("{} Hz, resampled on load", self.sample_rate)));
+        }
+
+        issues
+    }
+
+    /// Number of frames (one sample for every channel) in the file
+    pub fn frame_count(&self) -> usize {
+        self.data.len() / self.block_align as usize
+    }
+
+    /// Duration of the sample in seconds
+    pub fn duration(&self) -> f64 {
+        if self.sample_rate == 0 {
+            0.0
+        } else {
+            self.frame_count() as f64 / self.sample_rate as f64
+        }
+    }
+
+    /// Keep only the frames in [from, to[, cue points and sampler
+    /// loops are moved accordingly and dropped if outside of the kept
+    /// region. Other chunks are kept as is.
+    pub fn crop(&self, from: usize, to: usize) -> WavFile {
+        let frame_size = self.block_align as usize;
+        let to = to.min(self.frame_count());
+        let from = from.min(to);
+
+        let cues = self.cues.iter()
+            .filter(|c| (from .. to).contains(&(**c as usize)))
+            .map(|c| *c - from as u32)
+            .collect();
+
+        let other_chunks = self.other_chunks.iter()
+            .map(|chunk| match &chunk.id {
+                b"smpl" => RawChunk { id: chunk.id, content: crop_sampler_loops(&chunk.content, from, to) },
+                b"fact" if chunk.content.len() >= 4 => {
+                    let mut content = chunk.content.clone();
+                    content[0..4].copy_from_slice(&((to - from) as u32).to_le_bytes());
+                    RawChunk { id: chunk.id, content }
+                }
+                _ => chunk.clone()
+            })
+            .collect();
+
+        WavFile {
+            data: self.data[from * frame_size .. to * frame_size].to_vec(),
+            fmt_chunk: self.fmt_chunk.clone(),
+            cues,
+            other_chunks,
+            ..*self
+        }
+    }
+
+    /// Serialize the file, with fmt, cue, data and the other chunks
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut chunks = Vec::new();
+
+        let mut push_chunk = |id: &[u8], content: &[u8]| {
+            chunks.extend_from_slice(id);
+            chunks.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            chunks.extend_from_slice(content);
+            if content.len() & 1 == 1 {
+                chunks.push(0);
+            }
+        };
+
+        push_chunk(b"fmt ", &self.fmt_chunk);
+
+        if !self.cues.is_empty() {
+            let mut cue_chunk = Vec::new();
+            cue_chunk.extend_from_slice(&(self.cues.len() as u32).to_le_bytes());
+            for (i, position) in self.cues.iter().enumerate() {
+                cue_chunk.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+                cue_chunk.extend_from_slice(&position.to_le_bytes());
+                cue_chunk.extend_from_slice(b"data");
+                cue_chunk.extend_from_slice(&0u32.to_le_bytes());
+                cue_chunk.extend_from_slice(&0u32.to_le_bytes());
+                cue_chunk.extend_from_slice(&position.to_le_bytes());
+            }
+            push_chunk(b"cue ", &cue_chunk);
+        }
+
+        for chunk in self.other_chunks.iter() {
+            push_chunk(&chunk.id, &chunk.content);
+        }
+
+        push_chunk(b"data", &self.data);
+
+        let mut out = Vec::with_capacity(chunks.len() + 12);
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(&chunks);
+        out
+    }
+
+    /// Write the file on the disk, returning the written size.
+    pub fn write(&self, path: &Path) -> Result<u64, M8FstoErr> {
+        let bytes = self.to_bytes();
+        fs::write(path, &bytes)
+            .map_err(|e| M8FstoErr::SongSerializationError {
+                destination: format!("{:?}", path),
+                reason: format!("{:?}", e)
+            })?;
+
+        Ok(bytes.len() as u64)
+    }
+}
+
+/// Keep the loops of a `smpl` chunk inside the frames [from, to[,
+/// moving them to the start of the cropped sample.
+fn crop_sampler_loops(content: &[u8], from: usize, to: usize) -> Vec<u8> {
+    if content.len() < SAMPLER_HEADER_SIZE {
+        return content.to_vec()
+    }
+
+    let count = read_u32(content, 28) as usize;
+    let loops_end = (SAMPLER_HEADER_SIZE + count * SAMPLER_LOOP_SIZE).min(content.len());
+    let mut kept = vec![];
+
+    for sampler_loop in content[SAMPLER_HEADER_SIZE .. loops_end].chunks_exact(SAMPLER_LOOP_SIZE) {
+        let start = read_u32(sampler_loop, 8) as usize;
+        let end = read_u32(sampler_loop, 12) as usize;
+        if start < from || end >= to { continue; }
+
+        let mut moved = sampler_loop.to_vec();
+        moved[8..12].copy_from_slice(&((start - from) as u32).to_le_bytes());
+        moved[12..16].copy_from_slice(&((end - from) as u32).to_le_bytes());
+        kept.push(moved);
+    }
+
+    let mut cropped = content[.. SAMPLER_HEADER_SIZE].to_vec();
+    cropped[28..32].copy_from_slice(&(kept.len() as u32).to_le_bytes());
+    for sampler_loop in kept {
+        cropped.extend_from_slice(&sampler_loop);
+    }
+    cropped.extend_from_slice(&content[loops_end ..]);
+    cropped
+}
+
+/// Decoded audio, samples are interleaved and in the [-1, 1] range.
+pub struct Audio {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+
+    /// Cue points positions, in frames, kept through the conversions
+    pub cues: Vec<u32>
+}
+
+/// Half width, in input samples, of the resampling filter
+const RESAMPLE_HALF_TAPS : isize = 16;
+
+impl WavFile {
+    /// Build a PCM file from raw data
+    pub fn new_pcm(channels: u16, sample_rate: u32, bits_per_sample: u16, data: Vec<u8>) -> WavFile {
+        let block_align = channels * (bits_per_sample / 8);
+        let mut fmt_chunk = Vec::with_capacity(16);
+        fmt_chunk.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        fmt_chunk.extend_from_slice(&channels.to_le_bytes());
+        fmt_chunk.extend_from_slice(&sample_rate.to_le_bytes());
+        fmt_chunk.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        fmt_chunk.extend_from_slice(&block_align.to_le_bytes());
+        fmt_chunk.extend_from_slice(&bits_per_sample.to_le_bytes());
+
+        WavFile {
+            format_tag: WAVE_FORMAT_PCM,
+            channels,
+            sample_rate,
+            bits_per_sample,
+            block_align,
+            fmt_chunk,
+            data,
+            cues: vec![],
+            other_chunks: vec![]
+        }
+    }
+
+    /// Decode PCM and floating point samples
+    pub fn decode(&self) -> Result<Audio, String> {
+        let bytes_per_sample = (self.bits_per_sample as usize).div_ceil(8);
+        if bytes_per_sample == 0 || self.channels == 0 {
+            return Err(String::from("empty sample format"))
+        }
+
+        let frame_size = self.block_align as usize;
+        let channels = self.channels as usize;
+        if frame_size < bytes_per_sample * channels {
+            return Err(String::from("invalid block alignment"))
+        }
+
+        let decode_one : fn(&[u8]) -> f32 =
+            match (self.sample_format(), bytes_per_sample) {
+                (WAVE_FORMAT_PCM, 1) => |b| (b[0] as f32 - 128.0) / 128.0,
+                (WAVE_FORMAT_PCM, 2) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+                (WAVE_FORMAT_PCM, 3) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+                (WAVE_FORMAT_PCM, 4) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+                (WAVE_FORMAT_IEEE_FLOAT, 4) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                (WAVE_FORMAT_IEEE_FLOAT, 8) => |b|
+                    f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+                _ => return Err(format!("cannot decode {}", self.format_name()))
+            };
+
+        let mut samples = Vec::with_capacity(self.frame_count() * channels);
+        for frame in self.data.chunks_exact(frame_size) {
+            for c in 0 .. channels {
+                samples.push(decode_one(&frame[c * bytes_per_sample ..]));
+            }
+        }
+
+        Ok(Audio { channels: self.channels, sample_rate: self.sample_rate, samples, cues: self.cues.clone() })
+    }
+}
+
+impl Audio {
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /// Average all the channels in a single one
+    pub fn to_mono(&self) -> Audio {
+        let channels = self.channels.max(1) as usize;
+        let samples = self.samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+
+        Audio { channels: 1, sample_rate: self.sample_rate, samples, cues: self.cues.clone() }
+    }
+
+    /// Change the sample rate using a windowed sinc interpolation,
+    /// low-passing when reducing the rate. Cue points are moved to
+    /// the same time in the resampled audio.
+    pub fn resample(&self, sample_rate: u32) -> Audio {
+        if sample_rate == self.sample_rate || self.sample_rate == 0 {
+            return Audio {
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                samples: self.samples.clone(),
+                cues: self.cues.clone()
+            }
+        }
+
+        let channels = self.channels.max(1) as usize;
+        let in_frames = self.frame_count();
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        let cutoff = ratio.min(1.0);
+        let out_frames = (in_frames as f64 * ratio).round() as usize;
+        let half_taps = (RESAMPLE_HALF_TAPS as f64 / cutoff).ceil() as isize;
+
+        let mut samples = Vec::with_capacity(out_frames * channels);
+        for out_frame in 0 .. out_frames {
+            let center = out_frame as f64 / ratio;
+            let first = center.floor() as isize - half_taps + 1;
+
+            for c in 0 .. channels {
+                let mut acc = 0.0;
+                for i in first .. first + 2 * half_taps {
+                    if i < 0 || i as usize >= in_frames { continue; }
+
+                    let x = (i as f64 - center) * cutoff;
+                    let sinc = if x.abs() < 1e-9 { 1.0 } else {
+                        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                    };
+                    let window_pos = (i as f64 - center) / (half_taps as f64);
+                    let window = 0.5 + 0.5 * (std::f64::consts::PI * window_pos).cos();
+                    acc += self.samples[i as usize * channels + c] as f64 * sinc * window * cutoff;
+                }
+                samples.push(acc as f32);
+            }
+        }
+
+        let last_frame = out_frames.saturating_sub(1) as f64;
+        let mut cues : Vec<u32> = self.cues.iter()
+            .map(|c| (*c as f64 * ratio).round().min(last_frame) as u32)
+            .collect();
+        cues.dedup();
+
+        Audio { channels: self.channels, sample_rate, samples, cues }
+    }
+
+    /// Encode as an integer PCM WAV file, with 16 or 24 bits samples,
+    /// along with the cue points.
+    pub fn to_pcm_wav(&self, bits_per_sample: u16) -> WavFile {
+        let mut data = Vec::with_capacity(self.samples.len() * (bits_per_sample as usize / 8));
+
+        for s in self.samples.iter() {
+            let s = s.clamp(-1.0, 1.0) as f64;
+            if bits_per_sample == 24 {
+                let v = (s * 8388607.0).round() as i32;
+                data.extend_from_slice(&v.to_le_bytes()[0..3]);
+            } else {
+                let v = (s * 32767.0).round() as i16;
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+
+        let bits_per_sample = if bits_per_sample == 24 { 24 } else { 16 };
+        let mut wav = WavFile::new_pcm(self.channels, self.sample_rate, bits_per_sample, data);
+        wav.cues = self.cues.clone();
+        wav
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Audio, RawChunk, WavFile, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM};
+
+    /// Mono 16 bits file with a frame per value
+    fn mono16(values: &[i16]) -> WavFile {
+        let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        WavFile::new_pcm(1, 44100, 16, data)
+    }
+
+    fn sampler_chunk(loops: &[(u32, u32)]) -> RawChunk {
+        let mut content = vec![0u8; 36];
+        content[28..32].copy_from_slice(&(loops.len() as u32).to_le_bytes());
+        for (i, (start, end)) in loops.iter().enumerate() {
+            content.extend_from_slice(&(i as u32).to_le_bytes());
+            content.extend_from_slice(&0u32.to_le_bytes());
+            content.extend_from_slice(&start.to_le_bytes());
+            content.extend_from_slice(&end.to_le_bytes());
+            content.extend_from_slice(&[0u8; 8]);
+        }
+        RawChunk { id: *b"smpl", content }
+    }
+
+    fn loops_of(wav: &WavFile) -> Vec<(u32, u32)> {
+        let chunk = wav.other_chunks.iter().find(|c| &c.id == b"smpl").unwrap();
+        chunk.content[36..].chunks_exact(24)
+            .map(|l| (super::read_u32(l, 8), super::read_u32(l, 12)))
+            .collect()
+    }
+
+    #[test]
+    fn crop_keeps_the_frames_in_range() {
+        let wav = mono16(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(wav.crop(2, 5).data, mono16(&[2, 3, 4]).data);
+        assert_eq!(wav.crop(6, 100).frame_count(), 2);
+        assert_eq!(wav.crop(10, 4).frame_count(), 0);
+    }
+
+    #[test]
+    fn crop_moves_the_cue_points() {
+        let mut wav = mono16(&[0; 10]);
+        wav.cues = vec![0, 3, 5, 9];
+        assert_eq!(wav.crop(3, 9).cues, vec![0, 2]);
+    }
+
+    #[test]
+    fn crop_moves_the_sampler_loops() {
+        let mut wav = mono16(&[0; 10]);
+        wav.other_chunks.push(sampler_chunk(&[(1, 8), (4, 6), (2, 9)]));
+        assert_eq!(loops_of(&wav.crop(2, 9)), vec![(2, 4)]);
+    }
+
+    #[test]
+    fn unknown_chunks_are_written_back() {
+        let mut wav = mono16(&[1, 2, 3]);
+        wav.cues = vec![1];
+        wav.other_chunks.push(RawChunk { id: *b"LIST", content: b"INFOtxt".to_vec() });
+        wav.other_chunks.push(sampler_chunk(&[(0, 2)]));
+
+        let parsed = WavFile::parse(&wav.crop(0, 3).to_bytes()).unwrap();
+        assert_eq!(parsed.data, wav.data);
+        assert_eq!(parsed.cues, vec![1]);
+        assert_eq!(parsed.other_chunks.len(), 2);
+        assert_eq!(&parsed.other_chunks[0].id, b"LIST");
+        assert_eq!(parsed.other_chunks[0].content, b"INFOtxt");
+        assert_eq!(loops_of(&parsed), vec![(0, 2)]);
+    }
+
+    fn fmt_chunk(format_tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut fmt = vec![];
+        fmt.extend_from_slice(&format_tag.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&sample_rate.to_le_bytes());
+        fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+        fmt
+    }
+
+    /// RIFF file from its chunks
+    fn riff(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut content = b"WAVE".to_vec();
+        for (id, chunk) in chunks {
+            content.extend_from_slice(*id);
+            content.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            content.extend_from_slice(chunk);
+            if chunk.len() & 1 == 1 {
+                content.push(0);
+            }
+        }
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&content);
+        bytes
+    }
+
+    fn cue_chunk(positions: &[u32]) -> Vec<u8> {
+        let mut cue = (positions.len() as u32).to_le_bytes().to_vec();
+        for (i, position) in positions.iter().enumerate() {
+            cue.extend_from_slice(&(i as u32).to_le_bytes());
+            cue.extend_from_slice(&position.to_le_bytes());
+            cue.extend_from_slice(b"data");
+            cue.extend_from_slice(&[0u8; 8]);
+            cue.extend_from_slice(&position.to_le_bytes());
+        }
+        cue
+    }
+
+    fn samples16(wav: &WavFile) -> Vec<i16> {
+        wav.data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    #[test]
+    fn parse_and_write_back() {
+        let data : Vec<u8> = [100i16, -100, 2000, -2000, 0, 7].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let bytes = riff(&[
+            (b"fmt ", fmt_chunk(WAVE_FORMAT_PCM, 2, 48000, 16)),
+            (b"LIST", b"INFOISFT".to_vec()),
+            (b"cue ", cue_chunk(&[2, 1])),
+            (b"data", data.clone()),
+            (b"junk", vec![1, 2, 3])
+        ]);
+
+        let wav = WavFile::parse(&bytes).unwrap();
+        assert_eq!((wav.channels, wav.sample_rate, wav.bits_per_sample, wav.block_align), (2, 48000, 16, 4));
+        assert_eq!(wav.frame_count(), 3);
+        assert_eq!(wav.cues, vec![1, 2]);
+        assert_eq!(wav.data, data);
+        assert_eq!(wav.format_name(), "16-bit PCM");
+
+        let reparsed = WavFile::parse(&wav.to_bytes()).unwrap();
+        assert_eq!(reparsed.data, wav.data);
+        assert_eq!(reparsed.cues, wav.cues);
+        assert_eq!(reparsed.fmt_chunk, wav.fmt_chunk);
+        assert_eq!(reparsed.other_chunks.len(), 2);
+        assert_eq!(reparsed.other_chunks[1].content, vec![1, 2, 3]);
+        assert_eq!(reparsed.to_bytes(), wav.to_bytes());
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(WavFile::parse(b"RIFF\0\0\0\0AVI ").is_err());
+        assert!(WavFile::parse(&riff(&[(b"fmt ", fmt_chunk(WAVE_FORMAT_PCM, 1, 44100, 16))])).is_err());
+        assert!(WavFile::parse(&riff(&[(b"data", vec![0, 0])])).is_err());
+        assert!(WavFile::parse(&riff(&[(b"fmt ", fmt_chunk(WAVE_FORMAT_PCM, 0, 44100, 16)), (b"data", vec![])])).is_err());
+    }
+
+    #[test]
+    fn truncated_data_is_accepted() {
+        let mut bytes = riff(&[
+            (b"fmt ", fmt_chunk(WAVE_FORMAT_PCM, 1, 44100, 16)),
+            (b"data", vec![1, 0, 2, 0, 3, 0])
+        ]);
+        bytes.truncate(bytes.len() - 2);
+        assert_eq!(WavFile::parse(&bytes).unwrap().frame_count(), 2);
+    }
+
+    #[test]
+    fn pcm_24_bits_to_16_bits() {
+        let data : Vec<u8> = [0x7FFFFFi32, -0x800000, 0x400000, -0x400000, 0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes()[0..3].to_vec())
+            .collect();
+        let wav = WavFile::new_pcm(1, 44100, 24, data);
+
+        let converted = wav.decode().unwrap().to_pcm_wav(16);
+        assert_eq!(converted.bits_per_sample, 16);
+        assert_eq!(converted.block_align, 2);
+        assert_eq!(samples16(&converted), vec![32767, -32767, 16384, -16384, 0]);
+    }
+
+    #[test]
+    fn float_to_16_bits() {
+        let data : Vec<u8> = [0.5f32, -0.25, 1.5, -2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let bytes = riff(&[
+            (b"fmt ", fmt_chunk(WAVE_FORMAT_IEEE_FLOAT, 1, 44100, 32)),
+            (b"data", data)
+        ]);
+
+        let wav = WavFile::parse(&bytes).unwrap();
+        assert_eq!(wav.format_name(), "32-bit float");
+
+        let converted = wav.decode().unwrap().to_pcm_wav(16);
+        assert_eq!(samples16(&converted), vec![16384, -8192, 32767, -32767]);
+    }
+
+    #[test]
+    fn pcm_16_bits_to_24_bits() {
+        let converted = mono16(&[16384, -32768]).decode().unwrap().to_pcm_wav(24);
+        assert_eq!(converted.bits_per_sample, 24);
+        assert_eq!(converted.data, vec![0x00, 0x00, 0x40, 0x01, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn cues_are_written_with_the_converted_audio() {
+        let audio = Audio { channels: 2, sample_rate: 96000, samples: vec![0.5; 200 * 2], cues: vec![0, 50, 120] };
+        let mono = audio.to_mono();
+        assert_eq!((mono.channels, mono.frame_count()), (1, 200));
+
+        let resampled = mono.resample(48000);
+        assert_eq!(resampled.frame_count(), 100);
+        assert_eq!(resampled.cues, vec![0, 25, 60]);
+
+        let wav = WavFile::parse(&resampled.to_pcm_wav(16).to_bytes()).unwrap();
+        assert_eq!(wav.sample_rate, 48000);
+        assert_eq!(wav.cues, vec![0, 25, 60]);
+    }
+}